@@ -0,0 +1,430 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use reqwest::{blocking as req, header};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::{API_DANMAKU_SEG, API_PLAYER_INFO, API_STREAM_URL, API_USER_INFO, API_VIDEO_INFO,
+            API_VIDEO_TAGS, HTTP_USER_AGENT};
+use crate::danmaku::{parse_protobuf, Danmaku};
+use crate::download::{download_resumable, DownloadOptions};
+use crate::error::{Error, Result};
+use crate::mux::{mux, Chapter, Container, MuxOptions};
+use crate::stream::{AudioStream, Quality, StreamInfo, VideoStream};
+use crate::subtitle::{Subtitle, SubtitleTrack};
+use crate::video::{PageInfo, VideoId, VideoInfo};
+use crate::wbi::wbi_sign_para;
+
+pub enum UserState {
+    Vip(String),
+    User(String),
+    None,
+}
+
+pub struct UserInfo {
+    pub state: UserState,
+    pub img_url: String,
+    pub sub_url: String,
+}
+
+// 下载器客户端，持有带Cookie的HTTP客户端和当前登录用户的信息
+pub struct Client {
+    client: req::Client,
+    user_info: UserInfo,
+    download_options: DownloadOptions,
+}
+
+impl Client {
+    // 根据Cookie中的SESSDATA构造客户端，同时验证Cookie有效性及获取用户信息
+    pub fn new(sessdata: &str) -> Result<Self> {
+        let mut headers = header::HeaderMap::new();
+        let cookie = match header::HeaderValue::from_str(&format!("SESSDATA={}", sessdata)) {
+            Ok(t) => t,
+            Err(_) => return Err(Error::InvalidInput("Cookie格式有误".into()))
+        };
+        headers.insert(header::COOKIE, cookie);
+        let client = req::Client::builder()
+            .user_agent(HTTP_USER_AGENT)
+            .default_headers(headers)
+            .build()?;
+        let user_info = get_user_info(&client)?;
+        Ok(Self { client, user_info, download_options: DownloadOptions::default() })
+    }
+
+    pub fn user_info(&self) -> &UserInfo {
+        &self.user_info
+    }
+
+    pub fn set_download_options(&mut self, options: DownloadOptions) {
+        self.download_options = options;
+    }
+
+    // 获取视频信息，也用于预检视频是否有效
+    pub fn get_video_info(&self, video_id: &VideoId) -> Result<VideoInfo> {
+        #[derive(Deserialize)]
+        struct RawOwner {
+            name: String,
+        }
+        #[derive(Deserialize)]
+        struct RawPage {
+            cid: u32,
+            page: u32,
+            part: String,
+            #[serde(default)]
+            duration: u32,
+            first_frame: Option<String>,
+        }
+        #[derive(Deserialize)]
+        struct RawInfo {
+            bvid: String,
+            aid: u64,
+            title: String,
+            owner: RawOwner,
+            pic: String,
+            #[serde(default)]
+            desc: String,
+            #[serde(default)]
+            pubdate: i64,
+            #[serde(default)]
+            tname: String,
+            pages: Vec<RawPage>,
+        }
+        #[derive(Deserialize)]
+        struct RawTag {
+            tag_name: String,
+        }
+        let res: RawInfo = send_api(self.client.get(API_VIDEO_INFO)
+            .query(&[("bvid", video_id.bvid()?)]))?;
+        // 标签只用于元数据，获取失败时不影响下载
+        let tags: Vec<RawTag> = send_api(self.client.get(API_VIDEO_TAGS).query(&[("bvid", &res.bvid)]))
+            .unwrap_or_default();
+        let mut pages: Vec<PageInfo> = Vec::new();
+        for i in res.pages.iter() {
+            pages.push(PageInfo {
+                title: String::from(&i.part),
+                cid: i.cid,
+                p: i.page,
+                duration: i.duration,
+                first_frame: i.first_frame.clone().filter(|t| !t.is_empty()),
+            })
+        }
+        Ok(VideoInfo {
+            bvid: res.bvid,
+            aid: res.aid,
+            title: res.title,
+            uploader: res.owner.name,
+            cover: res.pic,
+            desc: res.desc,
+            pubdate: res.pubdate,
+            tname: res.tname,
+            tags: tags.into_iter().map(|t| t.tag_name).collect(),
+            pages,
+        })
+    }
+
+    // 获取某个分P的视频流信息，按当前用户身份请求能拿到的最高规格
+    pub fn get_stream_url(&self, bvid: &str, cid: u32) -> Result<StreamInfo> {
+        let mut quality_flag = match self.user_info.state {
+            UserState::None => vec![("qn".to_string(), "64".to_string()), ("fnval".to_string(), "16".to_string())],
+            UserState::User(_) => vec![("qn".to_string(), "80".to_string()), ("fnval".to_string(), "16".to_string())],
+            UserState::Vip(_) => vec![("qn".to_string(), "127".to_string()), ("fnval".to_string(), "4048".to_string()),
+                                      ("fourk".to_string(), "1".to_string()),
+            ]
+        };
+        let mut paras = vec![("bvid".to_string(), bvid.to_string()),
+                             ("cid".to_string(), cid.to_string())];
+        paras.append(&mut quality_flag);
+        let paras = wbi_sign_para(paras, &self.user_info.img_url, &self.user_info.sub_url)?;
+        #[derive(Deserialize)]
+        struct RawVideo {
+            id: i32,
+            base_url: String,
+            backup_url: Option<Vec<String>>,
+            codecid: i32,
+            #[serde(default)]
+            width: u32,
+            #[serde(default)]
+            height: u32,
+        }
+        #[derive(Deserialize)]
+        struct RawAudio {
+            id: i32,
+            base_url: String,
+            backup_url: Option<Vec<String>>,
+            #[serde(default)]
+            codecs: String,
+            #[serde(default)]
+            bandwidth: u64,
+        }
+        #[derive(Deserialize)]
+        struct RawFlac {
+            audio: Option<RawAudio>,
+        }
+        #[derive(Deserialize)]
+        struct RawDolby {
+            audio: Option<Vec<RawAudio>>,
+        }
+        #[derive(Deserialize)]
+        struct RawDash {
+            video: Vec<RawVideo>,
+            audio: Vec<RawAudio>,
+            dolby: Option<RawDolby>,
+            flac: Option<RawFlac>,
+        }
+        #[derive(Deserialize)]
+        struct RawData {
+            accept_description: Vec<String>,
+            accept_quality: Vec<i32>,
+            dash: RawDash,
+        }
+        let res: RawData = send_api(self.client.get(API_STREAM_URL).query(&paras))?;
+        let to_audio = |t: RawAudio| AudioStream {
+            id: t.id,
+            codecs: t.codecs,
+            bandwidth: t.bandwidth,
+            base_url: t.base_url,
+            backup_url: t.backup_url.unwrap_or_default(),
+        };
+        let dash = res.dash;
+        Ok(StreamInfo {
+            qualities: res.accept_quality.into_iter()
+                .zip(res.accept_description)
+                .map(|(id, description)| Quality { id, description })
+                .collect(),
+            video: dash.video.into_iter().map(|t| VideoStream {
+                id: t.id,
+                codecid: t.codecid,
+                width: t.width,
+                height: t.height,
+                base_url: t.base_url,
+                backup_url: t.backup_url.unwrap_or_default(),
+            }).collect(),
+            audio: dash.audio.into_iter().map(to_audio).collect(),
+            flac: dash.flac.and_then(|t| t.audio).map(to_audio),
+            dolby: dash.dolby.and_then(|t| t.audio).and_then(|t| t.into_iter().next()).map(to_audio),
+        })
+    }
+
+    // 获取某个分P的字幕轨道列表，AI生成的字幕（ai-zh等）只有登录后才会返回
+    pub fn get_subtitles(&self, bvid: &str, cid: u32) -> Result<Vec<SubtitleTrack>> {
+        #[derive(Deserialize)]
+        struct RawTrack {
+            lan: String,
+            lan_doc: String,
+            #[serde(default)]
+            subtitle_url: String,
+        }
+        #[derive(Deserialize)]
+        struct RawSubtitle {
+            #[serde(default)]
+            subtitles: Vec<RawTrack>,
+        }
+        #[derive(Deserialize)]
+        struct RawData {
+            subtitle: Option<RawSubtitle>,
+        }
+        let res: RawData = self.get_player_info(bvid, cid)?;
+        Ok(res.subtitle.map(|t| t.subtitles).unwrap_or_default().into_iter()
+            // 未登录时AI字幕的地址为空
+            .filter(|t| !t.subtitle_url.is_empty())
+            .map(|t| SubtitleTrack {
+                lan: t.lan,
+                lan_doc: t.lan_doc,
+                // 接口返回的是省略协议的“//aisubtitle.hdslb.com/...”
+                url: if t.subtitle_url.starts_with("//") {
+                    format!("https:{}", t.subtitle_url)
+                } else {
+                    t.subtitle_url
+                },
+            })
+            .collect())
+    }
+
+    // 获取某个分P的视频看点（UP主划分的章节），没有时返回空列表
+    pub fn get_chapters(&self, bvid: &str, cid: u32) -> Result<Vec<Chapter>> {
+        #[derive(Deserialize)]
+        struct RawViewPoint {
+            from: u32,
+            to: u32,
+            content: String,
+        }
+        #[derive(Deserialize)]
+        struct RawData {
+            view_points: Option<Vec<RawViewPoint>>,
+        }
+        let res: RawData = self.get_player_info(bvid, cid)?;
+        Ok(res.view_points.unwrap_or_default().into_iter()
+            .filter(|t| t.to > t.from)
+            .map(|t| Chapter { start: t.from as f64, end: t.to as f64, title: t.content })
+            .collect())
+    }
+
+    // 请求播放器接口，字幕、视频看点等都在这个接口里，T为需要的部分字段
+    fn get_player_info<T: DeserializeOwned>(&self, bvid: &str, cid: u32) -> Result<T> {
+        let paras = vec![("bvid".to_string(), bvid.to_string()),
+                         ("cid".to_string(), cid.to_string())];
+        let paras = wbi_sign_para(paras, &self.user_info.img_url, &self.user_info.sub_url)?;
+        send_api(self.client.get(API_PLAYER_INFO).query(&paras))
+    }
+
+    // 下载字幕轨道的JSON内容
+    pub fn get_subtitle(&self, track: &SubtitleTrack) -> Result<Subtitle> {
+        let res = self.client.get(&track.url).send()?.error_for_status()?;
+        Ok(serde_json::from_str(&res.text()?)?)
+    }
+
+    // 通过分段弹幕接口获取某个分P的全部弹幕，每段6分钟，duration为分P时长（秒）
+    pub fn get_danmaku(&self, cid: u32, duration: u32) -> Result<Vec<Danmaku>> {
+        let segments = duration.div_ceil(360).max(1);
+        let mut res = Vec::new();
+        for i in 1..=segments {
+            let data = self.client.get(API_DANMAKU_SEG)
+                .query(&[("type", 1), ("oid", cid), ("segment_index", i)])
+                .send()?.error_for_status()?.bytes()?;
+            res.append(&mut parse_protobuf(&data)?);
+        }
+        Ok(res)
+    }
+
+    // 下载封面等图片到dest
+    pub fn download_image(&self, url: &str, dest: &Path) -> Result<()> {
+        let res = self.client.get(url).send()?.error_for_status()?;
+        if let Some(t) = dest.parent() {
+            fs::create_dir_all(t)?;
+        }
+        fs::write(dest, res.bytes()?)?;
+        Ok(())
+    }
+
+    // 下载某个分P选好的视频流和音频流，并合并成output（不含扩展名）加上容器扩展名的文件，返回最终文件路径
+    // 临时文件按bvid、cid和流id命名，中断后再次下载会自动续传，合并成功后删除
+    // 容器装不下所选音频（如MP4与Hi-Res无损）时直接报错，由调用方换用MKV或普通音质
+    // options中除了输出容器，还可以带上合并时额外写入的内容，如内嵌字幕
+    pub fn download_video(&self, bvid: &str, cid: u32, video: &VideoStream, audio: &AudioStream,
+                          output: &Path, options: &MuxOptions) -> Result<PathBuf> {
+        if !options.container.supports_audio(audio.codec()) {
+            return Err(Error::InvalidInput(format!("{}格式不支持{}音频", options.container, audio.codec())));
+        }
+        let video_dest = self.download_video_stream(bvid, cid, video)?;
+        let audio_dest = self.download_audio_stream(bvid, cid, audio)?;
+        let output = prepare_output(output, options.container)?;
+        mux(&[&video_dest, &audio_dest], &output, options)?;
+        fs::remove_file(&video_dest)?;
+        fs::remove_file(&audio_dest)?;
+        Ok(output)
+    }
+
+    // 只下载某个分P选好的音频流，按音频编码放进对应的容器（AAC为m4a，无损为flac，杜比为eac3），返回最终文件路径
+    // options中的容器会被替换为音频编码对应的容器
+    pub fn download_audio(&self, bvid: &str, cid: u32, audio: &AudioStream, output: &Path,
+                          options: &MuxOptions) -> Result<PathBuf> {
+        let audio_dest = self.download_audio_stream(bvid, cid, audio)?;
+        let options = MuxOptions { container: Container::for_audio(audio.codec()), ..options.clone() };
+        let output = prepare_output(output, options.container)?;
+        mux(&[&audio_dest], &output, &options)?;
+        fs::remove_file(&audio_dest)?;
+        Ok(output)
+    }
+
+    fn download_video_stream(&self, bvid: &str, cid: u32, video: &VideoStream) -> Result<PathBuf> {
+        let dest = temp_dir()?.join(format!("{}_{}_{}_{}.m4v", bvid, cid, video.id, video.codecid));
+        download_resumable(&self.client, &video.urls(), cid, &dest, &self.download_options, &|| {
+            self.get_stream_url(bvid, cid)?.video.into_iter()
+                .find(|t| t.id == video.id && t.codecid == video.codecid)
+                .map(|t| t.urls())
+                .ok_or(Error::UnexpectedResponse("重新获取下载地址时找不到原来的视频流".into()))
+        })?;
+        Ok(dest)
+    }
+
+    fn download_audio_stream(&self, bvid: &str, cid: u32, audio: &AudioStream) -> Result<PathBuf> {
+        let dest = temp_dir()?.join(format!("{}_{}_{}.m4a", bvid, cid, audio.id));
+        download_resumable(&self.client, &audio.urls(), cid, &dest, &self.download_options, &|| {
+            self.get_stream_url(bvid, cid)?.audio_by_id(audio.id)
+                .map(|t| t.urls())
+                .ok_or(Error::UnexpectedResponse("重新获取下载地址时找不到原来的音频流".into()))
+        })?;
+        Ok(dest)
+    }
+}
+
+// 下载用的临时目录
+fn temp_dir() -> Result<PathBuf> {
+    let mut temp_dir = std::env::temp_dir();
+    temp_dir.push("rust_bilidown");
+    fs::create_dir_all(&temp_dir)?;
+    Ok(temp_dir)
+}
+
+// 给输出路径加上容器对应的扩展名，并创建所在目录
+fn prepare_output(output: &Path, container: Container) -> Result<PathBuf> {
+    if let Some(t) = output.parent() {
+        fs::create_dir_all(t)?;
+    }
+    let mut output = output.as_os_str().to_owned();
+    output.push(".");
+    output.push(container.extension());
+    Ok(PathBuf::from(output))
+}
+
+// B站接口的通用响应结构
+#[derive(Deserialize)]
+struct ApiResponse<T> {
+    code: i32,
+    #[serde(default)]
+    message: String,
+    data: Option<T>,
+}
+
+// 发送请求并解析B站接口的通用响应，code非0时返回接口错误
+fn send_api<T: DeserializeOwned>(request: req::RequestBuilder) -> Result<T> {
+    let res = request.send()?.error_for_status()?;
+    let res: ApiResponse<T> = serde_json::from_str(&res.text()?)?;
+    if res.code != 0 {
+        return Err(Error::Api { code: res.code, message: res.message });
+    }
+    res.data.ok_or(Error::UnexpectedResponse("缺少data字段".into()))
+}
+
+// 校验Cookie是否有效
+fn get_user_info(client: &req::Client) -> Result<UserInfo> {
+    #[derive(Deserialize)]
+    struct WbiImg {
+        img_url: String,
+        sub_url: String,
+    }
+    #[derive(Deserialize)]
+    #[allow(non_snake_case)]
+    struct RawData {
+        isLogin: bool,
+        wbi_img: WbiImg,
+        #[serde(default)]
+        uname: String,
+        #[serde(default)]
+        vipStatus: u8,
+    }
+    // 未登录时code为-101，但data里依然带有Wbi签名所需的信息，所以这里不走send_api
+    let res = client.get(API_USER_INFO).send()?.error_for_status()?;
+    let res: ApiResponse<RawData> = serde_json::from_str(&res.text()?)?;
+    let data = match res.data {
+        Some(t) if res.code == 0 || res.code == -101 => t,
+        _ => return Err(Error::Api { code: res.code, message: res.message })
+    };
+    let state = if res.code == -101 && !data.isLogin {
+        UserState::None
+    } else if res.code != 0 || !data.isLogin {
+        return Err(Error::UnexpectedResponse("登录状态与返回码不符".into()));
+    } else if data.vipStatus == 0 {
+        UserState::User(data.uname)
+    } else if data.vipStatus == 1 {
+        UserState::Vip(data.uname)
+    } else {
+        return Err(Error::UnexpectedResponse(format!("用户已登录，但会员状态未知：{}", data.vipStatus)));
+    };
+    Ok(UserInfo {
+        state,
+        img_url: data.wbi_img.img_url,
+        sub_url: data.wbi_img.sub_url,
+    })
+}
@@ -0,0 +1,574 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use fancy_regex::Regex;
+
+use crate::error::{Error, Result};
+use crate::subtitle::{ass_escape, ass_timestamp};
+
+// 弹幕的显示位置，对应弹幕的mode字段
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DanmakuMode {
+    // 1、2、3为从右向左滚动，6为从左向右滚动，这里统一当作滚动弹幕
+    Scroll,
+    // 4
+    Bottom,
+    // 5
+    Top,
+    // 7为高级弹幕，8为代码弹幕，9为BAS弹幕，无法转换为ASS
+    Special,
+}
+
+impl DanmakuMode {
+    pub fn from_id(id: i32) -> Self {
+        match id {
+            1..=3 | 6 => DanmakuMode::Scroll,
+            4 => DanmakuMode::Bottom,
+            5 => DanmakuMode::Top,
+            _ => DanmakuMode::Special,
+        }
+    }
+}
+
+// 一条弹幕，除了转换需要的字段，还保留了重新生成XML所需的字段
+#[derive(Clone)]
+pub struct Danmaku {
+    pub id: u64,
+    // 出现时间（秒）
+    pub time: f64,
+    // 原始的模式代码，显示位置见kind
+    pub mode: i32,
+    // 字号，标准为25，小字为18，大字为36
+    pub size: u32,
+    // 颜色，0xRRGGBB
+    pub color: u32,
+    // 发送时间（Unix时间戳）
+    pub ctime: i64,
+    // 弹幕池，0为普通池，1为字幕池，2为特殊池
+    pub pool: i32,
+    // 发送者mid的哈希
+    pub mid_hash: String,
+    // 屏蔽权重，0到10
+    pub weight: i32,
+    pub content: String,
+}
+
+impl Danmaku {
+    pub fn kind(&self) -> DanmakuMode {
+        DanmakuMode::from_id(self.mode)
+    }
+}
+
+impl Default for Danmaku {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            time: 0.0,
+            mode: 1,
+            size: 25,
+            color: 0xffffff,
+            ctime: 0,
+            pool: 0,
+            mid_hash: String::new(),
+            weight: 0,
+            content: String::new(),
+        }
+    }
+}
+
+// 弹幕的保存格式，XML为B站原始格式，便于存档；ASS可以作为字幕叠加在视频上
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DanmakuFormat {
+    Xml,
+    Ass,
+}
+
+impl DanmakuFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            DanmakuFormat::Xml => "xml",
+            DanmakuFormat::Ass => "ass",
+        }
+    }
+}
+
+impl fmt::Display for DanmakuFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DanmakuFormat::Xml => write!(f, "XML"),
+            DanmakuFormat::Ass => write!(f, "ASS"),
+        }
+    }
+}
+
+impl FromStr for DanmakuFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "xml" => Ok(DanmakuFormat::Xml),
+            "ass" => Ok(DanmakuFormat::Ass),
+            _ => Err(Error::InvalidInput(format!("不支持的弹幕格式：{}", s)))
+        }
+    }
+}
+
+// 弹幕转换为ASS时的样式和密度设置，画面按1920x1080排版
+#[derive(Clone)]
+pub struct DanmakuStyle {
+    // 标准字号（25）的弹幕在1080P画面中的字号，其余字号按比例缩放
+    pub font_size: u32,
+    // 不透明度，0到1
+    pub opacity: f64,
+    // 滚动弹幕从右边进入到左边离开的时间（秒）
+    pub scroll_duration: f64,
+    // 顶部、底部弹幕的停留时间（秒）
+    pub fixed_duration: f64,
+    // 弹幕可以占用的画面高度比例，0到1
+    pub area: f64,
+    // 同屏最多显示的弹幕数，为0时不限制，超出的弹幕直接丢弃
+    pub max_count: usize,
+}
+
+impl Default for DanmakuStyle {
+    fn default() -> Self {
+        Self {
+            font_size: 48,
+            opacity: 0.8,
+            scroll_duration: 8.0,
+            fixed_duration: 4.0,
+            area: 1.0,
+            max_count: 0,
+        }
+    }
+}
+
+// 弹幕过滤规则，从规则文件加载，保存弹幕前统一应用
+// 规则文件每行一条规则，#开头的行为注释：
+//   keyword 剧透        内容包含该关键词的弹幕
+//   regex ^前方高能     内容匹配该正则表达式的弹幕
+//   type top           该类型的弹幕，可选scroll、top、bottom、special
+//   dedupe 10          相同内容在10秒内重复出现时只保留第一条
+#[derive(Clone, Default)]
+pub struct DanmakuFilter {
+    pub keywords: Vec<String>,
+    pub regexes: Vec<Regex>,
+    pub modes: Vec<DanmakuMode>,
+    // 去重的时间窗口（秒），为0时不去重
+    pub dedupe_window: f64,
+}
+
+impl DanmakuFilter {
+    pub fn load(path: &Path) -> Result<Self> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    // 过滤弹幕，返回保留下来的弹幕，按出现时间排序
+    pub fn apply(&self, mut danmaku: Vec<Danmaku>) -> Vec<Danmaku> {
+        danmaku.sort_by(|a, b| a.time.total_cmp(&b.time));
+        // 每种内容最近一次保留的出现时间
+        let mut last_seen: HashMap<String, f64> = HashMap::new();
+        danmaku.retain(|t| {
+            if self.modes.contains(&t.kind()) ||
+                self.keywords.iter().any(|k| t.content.contains(k.as_str())) ||
+                self.regexes.iter().any(|r| r.is_match(&t.content).unwrap_or(false)) {
+                return false;
+            }
+            if self.dedupe_window > 0.0 {
+                let key = t.content.trim().to_string();
+                if let Some(last) = last_seen.get(&key) {
+                    if t.time - last < self.dedupe_window {
+                        return false;
+                    }
+                }
+                last_seen.insert(key, t.time);
+            }
+            true
+        });
+        danmaku
+    }
+}
+
+impl FromStr for DanmakuFilter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut res = DanmakuFilter::default();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |t: &str| Error::InvalidInput(format!("弹幕过滤规则第{}行有误：{}", i + 1, t));
+            let (kind, value) = match line.split_once(char::is_whitespace) {
+                Some((a, b)) => (a, b.trim()),
+                None => return Err(invalid("缺少规则内容")),
+            };
+            match kind.to_lowercase().as_str() {
+                "keyword" => res.keywords.push(value.to_string()),
+                "regex" => res.regexes.push(Regex::new(value).map_err(|e| invalid(&e.to_string()))?),
+                "type" => res.modes.push(match value.to_lowercase().as_str() {
+                    "scroll" => DanmakuMode::Scroll,
+                    "top" => DanmakuMode::Top,
+                    "bottom" => DanmakuMode::Bottom,
+                    "special" => DanmakuMode::Special,
+                    _ => return Err(invalid("弹幕类型只能是scroll、top、bottom或special")),
+                }),
+                "dedupe" => res.dedupe_window = value.parse().map_err(|_| invalid("去重时间窗口应为秒数"))?,
+                _ => return Err(invalid("未知的规则类型，可选keyword、regex、type或dedupe")),
+            }
+        }
+        Ok(res)
+    }
+}
+
+const PLAY_RES_X: f64 = 1920.0;
+const PLAY_RES_Y: f64 = 1080.0;
+
+// 生成B站XML格式的弹幕，用于存档
+pub fn to_xml(danmaku: &[Danmaku], cid: u32) -> String {
+    let mut res = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<i>\n");
+    let _ = writeln!(res, "<chatserver>chat.bilibili.com</chatserver><chatid>{}</chatid>", cid);
+    for t in danmaku.iter() {
+        let _ = writeln!(res, "<d p=\"{},{},{},{},{},{},{},{},{}\">{}</d>",
+                         t.time, t.mode, t.size, t.color, t.ctime, t.pool, t.mid_hash, t.id, t.weight,
+                         xml_escape(&t.content));
+    }
+    res.push_str("</i>\n");
+    res
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// 解析分段弹幕接口返回的protobuf（DmSegMobileReply），只取常用的字段
+// message DmSegMobileReply { repeated DanmakuElem elems = 1; }
+// message DanmakuElem {
+//     int64 id = 1; int32 progress = 2; int32 mode = 3; int32 fontsize = 4; uint32 color = 5;
+//     string midHash = 6; string content = 7; int64 ctime = 8; int32 weight = 9; int32 pool = 11; ...
+// }
+pub fn parse_protobuf(data: &[u8]) -> Result<Vec<Danmaku>> {
+    let mut res = Vec::new();
+    let mut reader = ProtoReader::new(data);
+    while let Some((field, wire)) = reader.key()? {
+        if field != 1 || wire != 2 {
+            reader.skip(wire)?;
+            continue;
+        }
+        let mut elem = ProtoReader::new(reader.bytes()?);
+        let mut danmaku = Danmaku::default();
+        while let Some((field, wire)) = elem.key()? {
+            match (field, wire) {
+                (1, 0) => danmaku.id = elem.varint()?,
+                (2, 0) => danmaku.time = elem.varint()? as i32 as f64 / 1000.0,
+                (3, 0) => danmaku.mode = elem.varint()? as i32,
+                (4, 0) => danmaku.size = elem.varint()? as u32,
+                (5, 0) => danmaku.color = elem.varint()? as u32,
+                (6, 2) => danmaku.mid_hash = String::from_utf8_lossy(elem.bytes()?).into_owned(),
+                (7, 2) => danmaku.content = String::from_utf8_lossy(elem.bytes()?).into_owned(),
+                (8, 0) => danmaku.ctime = elem.varint()? as i64,
+                (9, 0) => danmaku.weight = elem.varint()? as i32,
+                (11, 0) => danmaku.pool = elem.varint()? as i32,
+                _ => elem.skip(wire)?,
+            }
+        }
+        res.push(danmaku);
+    }
+    Ok(res)
+}
+
+// 最简单的protobuf读取器，只支持解析弹幕用到的几种wire type
+struct ProtoReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ProtoReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    // 读取字段序号和wire type，数据读完时返回None
+    fn key(&mut self) -> Result<Option<(u64, u8)>> {
+        if self.pos >= self.data.len() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        Ok(Some((key >> 3, (key & 7) as u8)))
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut res = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.data.get(self.pos).ok_or_else(malformed)?;
+            self.pos += 1;
+            res |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(res);
+            }
+        }
+        Err(malformed())
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.varint()? as usize;
+        let end = self.pos.checked_add(len).filter(|t| *t <= self.data.len()).ok_or_else(malformed)?;
+        let res = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(res)
+    }
+
+    fn skip(&mut self, wire: u8) -> Result<()> {
+        let len = match wire {
+            0 => return self.varint().map(|_| ()),
+            1 => 8,
+            2 => return self.bytes().map(|_| ()),
+            5 => 4,
+            _ => return Err(malformed()),
+        };
+        if self.pos + len > self.data.len() {
+            return Err(malformed());
+        }
+        self.pos += len;
+        Ok(())
+    }
+}
+
+fn malformed() -> Error {
+    Error::UnexpectedResponse("弹幕数据格式有误".into())
+}
+
+// 转换为ASS字幕，滚动弹幕从右向左移动，顶部、底部弹幕居中停留
+// 每种弹幕各自按行排布，放不下或超出同屏数量限制的弹幕直接丢弃
+pub fn to_ass(danmaku: &[Danmaku], style: &DanmakuStyle) -> String {
+    let alpha = ((1.0 - style.opacity.clamp(0.0, 1.0)) * 255.0).round() as u8;
+    let mut res = String::new();
+    let _ = write!(res, "[Script Info]
+ScriptType: v4.00+
+PlayResX: {}
+PlayResY: {}
+WrapStyle: 2
+
+[V4+ Styles]
+Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
+Style: Danmaku,Microsoft YaHei,{},&H{:02X}FFFFFF,&H{:02X}FFFFFF,&H{:02X}000000,&H{:02X}000000,1,0,0,0,100,100,0,0,1,1.5,0,7,0,0,0,1
+
+[Events]
+Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+", PLAY_RES_X, PLAY_RES_Y, style.font_size, alpha, alpha, alpha, alpha);
+
+    let mut sorted: Vec<&Danmaku> = danmaku.iter().filter(|t| t.kind() != DanmakuMode::Special).collect();
+    sorted.sort_by(|a, b| a.time.total_cmp(&b.time));
+    let line_height = style.font_size as f64 * 1.2;
+    let rows = ((PLAY_RES_Y * style.area.clamp(0.0, 1.0)) / line_height).floor().max(1.0) as usize;
+    // 每一行最后一条弹幕的（出现时间、宽度），用于判断新弹幕能否放进这一行
+    let mut scroll_rows: Vec<Option<(f64, f64)>> = vec![None; rows];
+    let mut top_rows: Vec<f64> = vec![f64::MIN; rows];
+    let mut bottom_rows: Vec<f64> = vec![f64::MIN; rows];
+    // 当前在屏幕上的弹幕的消失时间
+    let mut on_screen: Vec<f64> = Vec::new();
+
+    for t in sorted {
+        on_screen.retain(|end| *end > t.time);
+        if style.max_count > 0 && on_screen.len() >= style.max_count {
+            continue;
+        }
+        let size = style.font_size as f64 * t.size as f64 / 25.0;
+        let width = text_width(&t.content, size);
+        let (end, effect) = match t.kind() {
+            DanmakuMode::Scroll => {
+                let duration = style.scroll_duration;
+                let speed = (PLAY_RES_X + width) / duration;
+                // 前一条已经完全进入画面，且在它离开画面前不会被新弹幕追上
+                let row = scroll_rows.iter().position(|r| match r {
+                    None => true,
+                    Some((start, w)) => {
+                        let prev_speed = (PLAY_RES_X + w) / duration;
+                        t.time - start >= w / prev_speed && t.time + PLAY_RES_X / speed >= start + duration
+                    }
+                });
+                let row = match row {
+                    Some(r) => r,
+                    None => continue,
+                };
+                scroll_rows[row] = Some((t.time, width));
+                let y = row as f64 * line_height;
+                (t.time + duration, format!("\\move({:.0},{:.0},{:.0},{:.0})", PLAY_RES_X, y, -width, y))
+            }
+            DanmakuMode::Top | DanmakuMode::Bottom => {
+                let fixed_rows = if t.kind() == DanmakuMode::Top { &mut top_rows } else { &mut bottom_rows };
+                let row = match fixed_rows.iter().position(|end| *end <= t.time) {
+                    Some(r) => r,
+                    None => continue,
+                };
+                fixed_rows[row] = t.time + style.fixed_duration;
+                let y = if t.kind() == DanmakuMode::Top {
+                    row as f64 * line_height
+                } else {
+                    PLAY_RES_Y - (row + 1) as f64 * line_height
+                };
+                (t.time + style.fixed_duration, format!("\\an8\\pos({:.0},{:.0})", PLAY_RES_X / 2.0, y))
+            }
+            DanmakuMode::Special => continue,
+        };
+        on_screen.push(end);
+        let mut tags = effect;
+        if t.size != 25 {
+            let _ = write!(tags, "\\fs{:.0}", size);
+        }
+        if t.color & 0xffffff != 0xffffff {
+            // ASS的颜色顺序为BGR
+            let (r, g, b) = ((t.color >> 16) & 0xff, (t.color >> 8) & 0xff, t.color & 0xff);
+            let _ = write!(tags, "\\c&H{:02X}{:02X}{:02X}&", b, g, r);
+            // 深色弹幕用白色描边，否则看不清
+            if r * 299 + g * 587 + b * 114 < 60000 {
+                tags.push_str("\\3c&HFFFFFF&");
+            }
+        }
+        let _ = writeln!(res, "Dialogue: 0,{},{},Danmaku,,0,0,0,,{{{}}}{}",
+                         ass_timestamp(t.time), ass_timestamp(end), tags, ass_escape(&t.content));
+    }
+    res
+}
+
+// 估算文字宽度，全角字符按一个字号宽，半角字符按半个字号宽
+fn text_width(text: &str, size: f64) -> f64 {
+    text.chars().map(|c| if c.is_ascii() { 0.5 } else { 1.0 }).sum::<f64>() * size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn danmaku(time: f64, mode: i32, content: &str) -> Danmaku {
+        Danmaku { time, mode, content: content.to_string(), ..Danmaku::default() }
+    }
+
+    fn contents(danmaku: &[Danmaku]) -> Vec<&str> {
+        danmaku.iter().map(|t| t.content.as_str()).collect()
+    }
+
+    #[test]
+    fn filter_parses_every_rule_kind() {
+        let filter: DanmakuFilter = "# 注释\n\nkeyword 剧透\nregex ^前方高能\ntype top\nTYPE Bottom\ndedupe 10"
+            .parse().unwrap();
+        assert_eq!(filter.keywords, vec!["剧透"]);
+        assert_eq!(filter.regexes.len(), 1);
+        assert!(filter.modes == vec![DanmakuMode::Top, DanmakuMode::Bottom]);
+        assert_eq!(filter.dedupe_window, 10.0);
+    }
+
+    #[test]
+    fn filter_rejects_invalid_rules() {
+        assert!("block 剧透".parse::<DanmakuFilter>().is_err());
+        assert!("keyword".parse::<DanmakuFilter>().is_err());
+        assert!("type left".parse::<DanmakuFilter>().is_err());
+        assert!("dedupe 十秒".parse::<DanmakuFilter>().is_err());
+        assert!("regex (".parse::<DanmakuFilter>().is_err());
+    }
+
+    #[test]
+    fn filter_drops_matching_danmaku() {
+        let filter: DanmakuFilter = "keyword 剧透\nregex ^前方高能\ntype top".parse().unwrap();
+        let res = filter.apply(vec![
+            danmaku(3.0, 1, "这里有剧透"),
+            danmaku(1.0, 1, "前方高能预警"),
+            danmaku(2.0, 5, "顶部弹幕"),
+            danmaku(4.0, 4, "高能前方"),
+            danmaku(0.5, 1, "正常弹幕"),
+        ]);
+        assert_eq!(contents(&res), vec!["正常弹幕", "高能前方"]);
+    }
+
+    #[test]
+    fn filter_dedupes_within_window() {
+        let filter: DanmakuFilter = "dedupe 10".parse().unwrap();
+        let res = filter.apply(vec![
+            danmaku(25.0, 1, "哈哈哈"),
+            danmaku(0.0, 1, "哈哈哈"),
+            danmaku(5.0, 1, " 哈哈哈 "),
+            danmaku(9.9, 1, "其他"),
+            danmaku(12.0, 1, "哈哈哈"),
+            danmaku(20.0, 1, "哈哈哈"),
+        ]);
+        // 窗口从上一次保留的时间算起：0保留，5丢弃，12保留，20丢弃，25保留
+        let times: Vec<f64> = res.iter().filter(|t| t.content.trim() == "哈哈哈").map(|t| t.time).collect();
+        assert_eq!(times, vec![0.0, 12.0, 25.0]);
+        assert_eq!(res.len(), 4);
+    }
+
+    fn varint(buf: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            buf.push((value as u8 & 0x7f) | 0x80);
+            value >>= 7;
+        }
+        buf.push(value as u8);
+    }
+
+    fn varint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+        varint(buf, field << 3);
+        varint(buf, value);
+    }
+
+    fn bytes_field(buf: &mut Vec<u8>, field: u64, value: &[u8]) {
+        varint(buf, field << 3 | 2);
+        varint(buf, value.len() as u64);
+        buf.extend_from_slice(value);
+    }
+
+    #[test]
+    fn protobuf_decodes_segment() {
+        let mut elem = Vec::new();
+        varint_field(&mut elem, 1, 1234567890123);
+        varint_field(&mut elem, 2, 65432);
+        varint_field(&mut elem, 3, 5);
+        varint_field(&mut elem, 4, 36);
+        varint_field(&mut elem, 5, 0xfe0302);
+        bytes_field(&mut elem, 6, b"abcd1234");
+        bytes_field(&mut elem, 7, "第一条弹幕".as_bytes());
+        varint_field(&mut elem, 8, 1700000000);
+        varint_field(&mut elem, 9, 3);
+        // 不关心的字段要能跳过
+        bytes_field(&mut elem, 10, b"action");
+        elem.push(12 << 3 | 5);
+        elem.extend_from_slice(&[1, 2, 3, 4]);
+        varint_field(&mut elem, 11, 1);
+        let mut second = Vec::new();
+        bytes_field(&mut second, 7, "第二条".as_bytes());
+
+        let mut data = Vec::new();
+        bytes_field(&mut data, 1, &elem);
+        varint_field(&mut data, 2, 7);
+        bytes_field(&mut data, 1, &second);
+
+        let res = parse_protobuf(&data).unwrap();
+        assert_eq!(res.len(), 2);
+        let t = &res[0];
+        assert_eq!(t.id, 1234567890123);
+        assert_eq!(t.time, 65.432);
+        assert!(t.kind() == DanmakuMode::Top);
+        assert_eq!((t.size, t.color, t.ctime, t.weight, t.pool), (36, 0xfe0302, 1700000000, 3, 1));
+        assert_eq!(t.mid_hash, "abcd1234");
+        assert_eq!(t.content, "第一条弹幕");
+        // 缺少的字段取默认值
+        assert_eq!((res[1].mode, res[1].size, res[1].time), (1, 25, 0.0));
+        assert_eq!(res[1].content, "第二条");
+    }
+
+    #[test]
+    fn protobuf_rejects_truncated_data() {
+        let mut data = Vec::new();
+        bytes_field(&mut data, 1, "弹幕".as_bytes());
+        data.truncate(data.len() - 1);
+        assert!(parse_protobuf(&data).is_err());
+        assert!(parse_protobuf(&[0x08, 0x80]).is_err());
+    }
+}
@@ -0,0 +1,367 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
+
+use indicatif::{ProgressBar, ProgressStyle};
+use reqwest::{blocking as req, header, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::HTTP_REFERER;
+use crate::error::{Error, Result};
+
+const PROGRESS_TEMPLATE: &str = "{msg} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})";
+const SPINNER_TEMPLATE: &str = "{msg} {spinner} [{elapsed_precise}] {bytes} ({bytes_per_sec})";
+const BUFFER_SIZE: usize = 64 * 1024;
+const MIN_SEGMENT_SIZE: u64 = 1024 * 1024;
+// 测速窗口，每个窗口结束时检查一次这段时间内的平均速度
+const SPEED_WINDOW_SECS: u64 = 10;
+// 分段下载时保存续传进度的间隔
+const SAVE_INTERVAL_SECS: u64 = 1;
+
+// 下载选项
+#[derive(Clone)]
+pub struct DownloadOptions {
+    // 单个流同时使用的连接数，B站CDN对单个连接限速，高码率视频适当调大可以明显加快下载
+    pub connections: u32,
+    // 单个连接的最低速度（字节/秒），低于该速度时切换到下一个镜像，为0时不检查
+    pub min_speed: u64,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self { connections: 4, min_speed: 32 * 1024 }
+    }
+}
+
+// 断点续传记录，以json形式保存在.part文件旁边
+#[derive(Serialize, Deserialize)]
+struct PartInfo {
+    urls: Vec<String>,
+    size: u64,
+    cid: u32,
+    // 各段已写入.part文件的字节数，段数即连接数
+    #[serde(default)]
+    progress: Vec<u64>,
+}
+
+// 创建下载进度条，服务器没有给出Content-Length时退化为只显示已下载大小和速度的转圈动画
+pub fn new_progress_bar(total: Option<u64>, msg: &str) -> ProgressBar {
+    let pb = match total {
+        Some(t) => ProgressBar::new(t).with_style(ProgressStyle::with_template(PROGRESS_TEMPLATE).unwrap()
+            .progress_chars("=> ")),
+        None => ProgressBar::new_spinner().with_style(ProgressStyle::with_template(SPINNER_TEMPLATE).unwrap()),
+    };
+    pb.set_message(msg.to_string());
+    pb
+}
+
+// 将一个流下载到指定文件，按块边下边写，不把整个文件读进内存，返回写入的字节数
+pub fn download_file(client: &req::Client, url: &str, dest: &Path) -> Result<u64> {
+    let mut res = client.get(url).header(header::REFERER, HTTP_REFERER).send()?.error_for_status()?;
+    let pb = new_progress_bar(res.content_length(), &file_name_of(dest));
+    let mut file = BufWriter::new(File::create(dest)?);
+    let written = write_body(&mut res, &mut file, &pb, 0)?;
+    pb.finish();
+    Ok(written)
+}
+
+// 依次尝试各个镜像下载整个文件，用于服务器不支持Range的情况
+fn download_file_mirrors(client: &req::Client, urls: &[String], dest: &Path) -> Result<u64> {
+    try_mirrors(urls, |url| download_file(client, url, dest))
+}
+
+// 支持断点续传、多连接分段和镜像切换的下载：urls为主地址加备用镜像，
+// 未完成的数据写在预先分配好大小的“文件名.part”里，每段直接写到自己的位置，不需要事后拼接，
+// “文件名.part.json”记录下载地址、文件大小、cid和各段进度，再次下载同一个cid时用Range请求从各段的断点处继续，
+// 某个镜像出错、超时或速度过慢时从断点处换下一个镜像继续，原地址过期时通过refresh重新获取一组新地址
+pub fn download_resumable(client: &req::Client, urls: &[String], cid: u32, dest: &Path, options: &DownloadOptions,
+                          refresh: &dyn Fn() -> Result<Vec<String>>) -> Result<u64> {
+    if dest.exists() {
+        return Ok(fs::metadata(dest)?.len());
+    }
+    let (part_path, meta_path) = part_paths(dest);
+    let mut info = match read_part_info(&meta_path) {
+        Some(t) if t.cid == cid && !t.urls.is_empty() => t,
+        _ => PartInfo { urls: urls.to_vec(), size: 0, cid, progress: Vec::new() },
+    };
+    if info.urls.is_empty() {
+        return Err(Error::UnexpectedResponse("没有可用的下载地址".into()));
+    }
+    if url_expired(&info.urls[0]) {
+        info.urls = refresh()?;
+    }
+    // 先探测文件大小和服务器是否支持Range，顺便检查地址是否还能用
+    let size = match try_mirrors(&info.urls, |url| probe_size(client, url)) {
+        Err(Error::HttpStatus(t)) if is_expired_status(t) => {
+            info.urls = refresh()?;
+            try_mirrors(&info.urls, |url| probe_size(client, url))?
+        }
+        t => t?
+    };
+    let size = match size {
+        Some(t) if t > 0 => t,
+        _ => {
+            // 不支持Range的服务器只能整个重新下载
+            let _ = fs::remove_file(&meta_path);
+            let written = download_file_mirrors(client, &info.urls, &part_path)?;
+            fs::rename(&part_path, dest)?;
+            return Ok(written);
+        }
+    };
+    // 文件大小与记录的不一致，说明已经不是同一个文件了，.part文件不在或大小不对时进度也不可信，之前下载的部分作废
+    let part_len = fs::metadata(&part_path).map(|t| t.len()).ok();
+    if size != info.size || info.progress.is_empty() || part_len != Some(size) {
+        info.size = size;
+        info.progress = vec![0; segment_count(size, options.connections) as usize];
+        File::create(&part_path)?.set_len(size)?;
+    }
+    save_part_info(&meta_path, &info)?;
+
+    let ranges = segment_ranges(size, info.progress.len() as u32);
+    let urls = info.urls.clone();
+    let state = Mutex::new(info);
+    let pb = new_progress_bar(Some(size), &file_name_of(dest));
+    let target = SegmentTarget { part_path: &part_path, meta_path: &meta_path, state: &state, pb: &pb };
+    let results: Vec<Result<()>> = std::thread::scope(|s| {
+        let handles: Vec<_> = ranges.iter().enumerate()
+            .map(|(index, &(start, end))| {
+                let (urls, target) = (&urls, &target);
+                s.spawn(move || download_range(client, urls, target, index, start, end, options))
+            })
+            .collect();
+        handles.into_iter().map(|t| t.join().unwrap()).collect()
+    });
+    if let Some(e) = results.into_iter().find_map(|t| t.err()) {
+        pb.abandon();
+        return Err(e);
+    }
+    pb.finish();
+
+    fs::rename(&part_path, dest)?;
+    fs::remove_file(&meta_path)?;
+    Ok(size)
+}
+
+// 判断B站CDN的签名地址是否已过期，地址中的deadline参数为过期时间戳
+pub fn url_expired(url: &str) -> bool {
+    let deadline = reqwest::Url::parse(url).ok().and_then(|t| t.query_pairs()
+        .find(|(k, _)| k == "deadline")
+        .and_then(|(_, v)| v.parse::<u64>().ok()));
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    matches!(deadline, Some(t) if now >= t)
+}
+
+// 地址过期时CDN一般返回403，个别节点返回404或410
+fn is_expired_status(status: StatusCode) -> bool {
+    status == StatusCode::FORBIDDEN || status == StatusCode::NOT_FOUND || status == StatusCode::GONE
+}
+
+// 请求第一个字节来探测文件大小，服务器不支持Range时返回None
+fn probe_size(client: &req::Client, url: &str) -> Result<Option<u64>> {
+    let res = client.get(url)
+        .header(header::REFERER, HTTP_REFERER)
+        .header(header::RANGE, "bytes=0-0")
+        .send()?
+        .error_for_status()?;
+    if res.status() == StatusCode::PARTIAL_CONTENT {
+        Ok(content_range_total(&res))
+    } else {
+        Ok(None)
+    }
+}
+
+// 分段下载的写入目标：.part文件、续传记录和各段的进度，各段共用一个进度条
+struct SegmentTarget<'a> {
+    part_path: &'a Path,
+    meta_path: &'a Path,
+    state: &'a Mutex<PartInfo>,
+    pb: &'a ProgressBar,
+}
+
+impl SegmentTarget<'_> {
+    fn progress(&self, index: usize) -> u64 {
+        self.state.lock().unwrap().progress[index]
+    }
+
+    fn set_progress(&self, index: usize, written: u64) -> io::Result<()> {
+        let mut info = self.state.lock().unwrap();
+        info.progress[index] = written;
+        save_part_info(self.meta_path, &info)
+    }
+}
+
+// 把第index段写到.part文件中该段的位置，定期以及结束时（包括出错）把已写入的字节数记到续传记录里
+struct SegmentWriter<'a> {
+    file: BufWriter<File>,
+    target: &'a SegmentTarget<'a>,
+    index: usize,
+    written: u64,
+    saved_at: Instant,
+}
+
+impl Write for SegmentWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        if self.saved_at.elapsed().as_secs() >= SAVE_INTERVAL_SECS {
+            self.flush()?;
+        }
+        Ok(n)
+    }
+
+    // 先把数据写进文件再记录进度，记录的进度不会超过实际写入的数据
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.saved_at = Instant::now();
+        self.target.set_progress(self.index, self.written)
+    }
+}
+
+impl Drop for SegmentWriter<'_> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+// 下载文件中[start, end]这一段，续传记录中该段已有的进度视为已下载的部分，出错时从断点处换下一个镜像继续
+fn download_range(client: &req::Client, urls: &[String], target: &SegmentTarget, index: usize, start: u64, end: u64,
+                  options: &DownloadOptions) -> Result<()> {
+    let len = end - start + 1;
+    let offset = target.progress(index);
+    if offset > len {
+        target.set_progress(index, 0)?;
+    } else {
+        target.pb.inc(offset);
+    }
+    let mut last_err = None;
+    for (i, url) in urls.iter().enumerate() {
+        // 最后一个镜像已经没有可切换的了，再慢也只能继续下
+        let min_speed = if i + 1 < urls.len() { options.min_speed } else { 0 };
+        match download_range_from(client, url, target, index, start, len, min_speed) {
+            Ok(_) => return Ok(()),
+            Err(e) => {
+                if i + 1 < urls.len() {
+                    target.pb.println(format!("{}，切换到下一个镜像", e));
+                }
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.unwrap_or(Error::UnexpectedResponse("没有可用的下载地址".into())))
+}
+
+fn download_range_from(client: &req::Client, url: &str, target: &SegmentTarget, index: usize, start: u64, len: u64,
+                       min_speed: u64) -> Result<()> {
+    let end = start + len - 1;
+    let offset = target.progress(index);
+    if offset == len {
+        return Ok(());
+    }
+    let mut res = client.get(url)
+        .header(header::REFERER, HTTP_REFERER)
+        .header(header::RANGE, format!("bytes={}-{}", start + offset, end))
+        .send()?
+        .error_for_status()?;
+    if res.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(Error::UnexpectedResponse("服务器不再支持分段下载".into()));
+    }
+    let mut file = OpenOptions::new().write(true).open(target.part_path)?;
+    file.seek(SeekFrom::Start(start + offset))?;
+    let mut writer = SegmentWriter {
+        file: BufWriter::new(file),
+        target,
+        index,
+        written: offset,
+        saved_at: Instant::now(),
+    };
+    let written = write_body(&mut res, &mut writer, target.pb, min_speed)? + offset;
+    if written != len {
+        return Err(Error::UnexpectedResponse(format!("下载不完整，已下载{}字节，共{}字节", written, len)));
+    }
+    Ok(())
+}
+
+// 从“Content-Range: bytes 100-199/200”中取出文件总大小
+fn content_range_total(res: &req::Response) -> Option<u64> {
+    res.headers().get(header::CONTENT_RANGE)?
+        .to_str().ok()?
+        .rsplit_once('/')?
+        .1.parse().ok()
+}
+
+// 把响应体写入文件，min_speed不为0时每个测速窗口检查一次速度，过慢则中止
+fn write_body(res: &mut req::Response, file: &mut impl Write, pb: &ProgressBar, min_speed: u64) -> Result<u64> {
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut written = 0;
+    let (mut window_start, mut window_bytes) = (Instant::now(), 0);
+    loop {
+        let n = res.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        file.write_all(&buffer[..n])?;
+        written += n as u64;
+        pb.inc(n as u64);
+        window_bytes += n as u64;
+        let elapsed = window_start.elapsed().as_secs();
+        if elapsed >= SPEED_WINDOW_SECS {
+            let speed = window_bytes / elapsed;
+            if min_speed > 0 && speed < min_speed {
+                file.flush()?;
+                return Err(Error::TooSlow(speed));
+            }
+            (window_start, window_bytes) = (Instant::now(), 0);
+        }
+    }
+    file.flush()?;
+    Ok(written)
+}
+
+// 依次用各个镜像执行f，直到有一个成功，全部失败时返回最后一个错误
+fn try_mirrors<T>(urls: &[String], mut f: impl FnMut(&str) -> Result<T>) -> Result<T> {
+    let mut last_err = None;
+    for url in urls.iter() {
+        match f(url) {
+            Ok(t) => return Ok(t),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or(Error::UnexpectedResponse("没有可用的下载地址".into())))
+}
+
+// 按连接数把文件分段，每段不小于MIN_SEGMENT_SIZE
+fn segment_count(size: u64, connections: u32) -> u32 {
+    let max = size.div_ceil(MIN_SEGMENT_SIZE).max(1);
+    (connections.max(1) as u64).min(max) as u32
+}
+
+// 把[0, size)平均分成segments段，返回每段的起止位置（闭区间）
+fn segment_ranges(size: u64, segments: u32) -> Vec<(u64, u64)> {
+    let step = size.div_ceil(segments as u64);
+    (0..segments as u64)
+        .map(|i| (i * step, ((i + 1) * step).min(size) - 1))
+        .collect()
+}
+
+fn part_paths(dest: &Path) -> (PathBuf, PathBuf) {
+    let mut part = dest.as_os_str().to_owned();
+    part.push(".part");
+    let mut meta = part.clone();
+    meta.push(".json");
+    (part.into(), meta.into())
+}
+
+fn read_part_info(meta_path: &Path) -> Option<PartInfo> {
+    serde_json::from_reader(File::open(meta_path).ok()?).ok()
+}
+
+fn save_part_info(meta_path: &Path, info: &PartInfo) -> io::Result<()> {
+    serde_json::to_writer(File::create(meta_path)?, info)?;
+    Ok(())
+}
+
+fn file_name_of(path: &Path) -> String {
+    path.file_name().map(|t| t.to_string_lossy().to_string()).unwrap_or_default()
+}
@@ -0,0 +1,89 @@
+use std::{fmt, io};
+
+use indicatif::HumanBytes;
+use reqwest::StatusCode;
+
+extern crate ffmpeg_next as ffmpeg;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// 下载器的错误类型，调用方可以按具体原因分别处理，Display输出面向用户的中文提示
+#[derive(Debug)]
+pub enum Error {
+    // 网络请求失败（连接、超时、读取响应体等）
+    Network(reqwest::Error),
+    // HTTP状态码不是2xx
+    HttpStatus(StatusCode),
+    // 响应内容与预期的JSON结构不符
+    Json(serde_json::Error),
+    // B站接口返回了非0的code
+    Api { code: i32, message: String },
+    // 响应结构正确，但内容无法理解
+    UnexpectedResponse(String),
+    // Wbi签名失败，一般是img_url/sub_url格式变了
+    WbiSign(String),
+    // 下载速度低于设定的最低速度（字节/秒）
+    TooSlow(u64),
+    // 文件读写失败
+    Io(io::Error),
+    // 用户输入的视频链接、av/BV号等无效
+    InvalidInput(String),
+    // ffmpeg处理失败
+    Ffmpeg(ffmpeg::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Network(_) => write!(f, "网络错误"),
+            Error::HttpStatus(t) => write!(f, "网络错误，HTTP状态码：{}", t.as_u16()),
+            Error::Json(_) => write!(f, "响应异常"),
+            Error::Api { code, message } => write!(f, "接口返回错误{}：{}", code, message),
+            Error::UnexpectedResponse(t) => write!(f, "无法理解的响应：{}", t),
+            Error::WbiSign(t) => write!(f, "Wbi签名失败：{}", t),
+            Error::TooSlow(t) => write!(f, "下载速度过慢：{}/s", HumanBytes(*t)),
+            Error::Io(t) => write!(f, "文件读写失败：{}", t),
+            Error::InvalidInput(t) => write!(f, "{}", t),
+            Error::Ffmpeg(t) => write!(f, "ffmpeg异常：{}", t),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Network(t) => Some(t),
+            Error::Json(t) => Some(t),
+            Error::Io(t) => Some(t),
+            Error::Ffmpeg(t) => Some(t),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(value: reqwest::Error) -> Self {
+        match value.status() {
+            Some(t) => Error::HttpStatus(t),
+            None => Error::Network(value),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(value: serde_json::Error) -> Self {
+        Error::Json(value)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Error::Io(value)
+    }
+}
+
+impl From<ffmpeg::Error> for Error {
+    fn from(value: ffmpeg::Error) -> Self {
+        Error::Ffmpeg(value)
+    }
+}
@@ -0,0 +1,57 @@
+/*
+ rust-bilidown
+ 个人第一个入门Rust的练手项目，一个B站下载器，基于CLI，支持下载大会员专属格式、支持分P视频、支持选择清晰度
+ 参考：https://github.com/SocialSisterYi/bilibili-API-collect
+
+ 本crate同时作为库提供，可以在其他项目中直接使用Client完成解析和下载
+*/
+
+pub mod client;
+pub mod danmaku;
+pub mod download;
+pub mod error;
+pub mod mux;
+pub mod stream;
+pub mod subtitle;
+pub mod template;
+pub mod video;
+pub mod wbi;
+
+pub use client::{Client, UserInfo, UserState};
+pub use danmaku::{Danmaku, DanmakuFilter, DanmakuFormat, DanmakuMode, DanmakuStyle};
+pub use download::DownloadOptions;
+pub use error::{Error, Result};
+pub use mux::{Chapter, Container, MuxOptions, SubtitleFile, chapter_text};
+pub use stream::{AudioCodec, AudioPreference, AudioStream, Codec, CodecPreference, Quality, QualityPolicy, StreamInfo, VideoStream};
+pub use subtitle::{Subtitle, SubtitleFormat, SubtitleLine, SubtitleTrack};
+pub use template::{OutputTemplate, TemplateFields, disambiguate, sanitize_file_name};
+pub use video::{PageInfo, VideoId, VideoIdValue, VideoInfo, extract_video_refs, av_to_bv, bv_to_av, format_date, parse_video_id};
+pub use wbi::wbi_sign_para;
+
+// 常量部分，主要用于正则表达式匹配和B站API
+// 前后不能紧挨字母数字，避免把BV号中间的“av”和数字当成av号
+pub const REG_BVID: &str = r"(?<![A-Za-z0-9])BV[A-Za-z0-9]{10}(?![A-Za-z0-9])";
+pub const REG_AVID: &str = r"(?<![A-Za-z0-9])av\d{1,16}(?![A-Za-z0-9])";
+// const REG_URL: &str = r"(.*)bilibili.com/video/(BV\w{10}|av\d{1,9})";
+pub const REG_URL: &str = r"(.*)bilibili.com/video/(BV\w{10}|av\d{1,16})(?=/|\?|$)";
+pub const REG_SHORT_URL: &str = r"(http(s|)://|^)b23.tv/(\w+)";
+// 从分享文案等任意文本中找出视频链接、短链接和av/bv号，只匹配ASCII字符，避免把紧跟在后面的中文吞进链接
+const REG_VIDEO_REF: &str = r"(?:https?://)?(?:[A-Za-z0-9-]+\.)*bilibili\.com/video/(?:BV[A-Za-z0-9]{10}|av\d{1,16})[A-Za-z0-9\-._~/?=&%#+]*|(?:https?://)?b23\.tv/[A-Za-z0-9]+|(?<![A-Za-z0-9])(?:BV[A-Za-z0-9]{10}|av\d{1,16})(?![A-Za-z0-9])";
+const REG_WBI_KEY: &str = r"(?<=i0.hdslb.com/bfs/wbi/)(\w+)(?=\.png)";
+const API_VIDEO_INFO: &str = "https://api.bilibili.com/x/web-interface/view";
+const API_VIDEO_TAGS: &str = "https://api.bilibili.com/x/tag/archive/tags";
+const API_STREAM_URL: &str = "https://api.bilibili.com/x/player/wbi/playurl";
+const API_DANMAKU_SEG: &str = "https://api.bilibili.com/x/v2/dm/web/seg.so";
+const API_PLAYER_INFO: &str = "https://api.bilibili.com/x/player/wbi/v2";
+const API_USER_INFO: &str = "https://api.bilibili.com/x/web-interface/nav";
+const HTTP_REFERER: &str = "https://www.bilibili.com";
+const HTTP_USER_AGENT: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.1 Safari/605.1.15";
+const WBI_KEY_TAB: [u8; 64] = [
+    46, 47, 18, 2, 53, 8, 23, 32, 15, 50, 10, 31, 58, 3, 45, 35, 27, 43, 5, 49, 33, 9,
+    42, 19, 29, 28, 14, 39, 12, 38, 41, 13, 37, 48, 7, 16, 24, 55, 40, 61, 26, 17, 0,
+    1, 60, 51, 30, 4, 22, 25, 54, 21, 56, 59, 6, 63, 57, 62, 11, 36, 20, 34, 44, 52
+];
+// av号与BV号互转用的码表和常量，见 https://github.com/SocialSisterYi/bilibili-API-collect/blob/master/docs/misc/bvid_desc.md
+const BV_ALPHABET: &[u8; 58] = b"FcwAPNKTMug3GV5Lj7EJnHpWsx4tb8haYeviqBz6rkCy12mUSDQX9RdoZf";
+const BV_XOR_CODE: u64 = 23442827791579;
+const BV_MAX_AID: u64 = 1 << 51;
@@ -4,12 +4,14 @@
  参考：https://github.com/SocialSisterYi/bilibili-API-collect
 */
 
-use std::{fmt, fs};
-use std::ops::Add;
-use std::time::SystemTime;
-use std::io::copy;
-use std::fs::File;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
+use clap::Parser;
 use colored::*;
 use fancy_regex::Regex;
 use inquire::{
@@ -20,98 +22,282 @@ use inquire::{
     Text,
     validator::Validation,
 };
-use reqwest::{blocking as req, header, redirect::Policy};
-use reqwest::blocking::Client;
-use serde::Deserialize;
-use serde_json as json;
-
-// 常量部分，主要用于正则表达式匹配和B站API
-const REG_BVID: &str = r"BV\w{10}";
-const REG_AVID: &str = r"av\d{1,9}";
-// const REG_URL: &str = r"(.*)bilibili.com/video/(BV\w{10}|av\d{1,9})";
-const REG_URL: &str = r"(.*)bilibili.com/video/(BV\w{10}|av\d{1,9})(?=/|\?|$)";
-const REG_SHORT_URL: &str = r"(http(s|)://|^)b23.tv/(\w+)";
-const REG_WBI_KEY: &str = r"(?<=i0.hdslb.com/bfs/wbi/)(\w+)(?=\.png)";
-const API_VIDEO_INFO: &str = "https://api.bilibili.com/x/web-interface/view";
-const API_STREAM_URL: &str = "https://api.bilibili.com/x/player/wbi/playurl";
-const API_USER_INFO: &str = "https://api.bilibili.com/x/web-interface/nav";
-const HTTP_REFERER: &str = "https://www.bilibili.com";
-const HTTP_USER_AGENT: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.1 Safari/605.1.15";
-const WBI_KEY_TAB: [u8; 64] = [
-    46, 47, 18, 2, 53, 8, 23, 32, 15, 50, 10, 31, 58, 3, 45, 35, 27, 43, 5, 49, 33, 9,
-    42, 19, 29, 28, 14, 39, 12, 38, 41, 13, 37, 48, 7, 16, 24, 55, 40, 61, 26, 17, 0,
-    1, 60, 51, 30, 4, 22, 25, 54, 21, 56, 59, 6, 63, 57, 62, 11, 36, 20, 34, 44, 52
-];
-
-enum VideoIdValue {
-    Avid(u32),
-    Bvid(String),
-}
-
-struct VideoId {
-    value: VideoIdValue,
-}
-
-impl VideoId {
-    fn get_key(&self) -> &str {
-        match self.value {
-            VideoIdValue::Avid(_) => "aid",
-            VideoIdValue::Bvid(_) => "bvid"
-        }
-    }
-    fn to_string(&self) -> String {
-        match &self.value {
-            VideoIdValue::Avid(t) => t.to_string(),
-            VideoIdValue::Bvid(t) => t.clone(),
-        }
-    }
-    fn new(av_or_bvid: &str) -> Result<Self, String> {
-        match Regex::new(REG_AVID).unwrap().captures(av_or_bvid).unwrap() {
-            Some(t) => match t[0][2..].parse::<u32>() {
-                Ok(t) => Ok(Self { value: VideoIdValue::Avid(t) }),
-                Err(t) => Err(t.to_string())
-            },
-            None => match Regex::new(REG_BVID).unwrap().captures(av_or_bvid).unwrap() {
-                Some(t) => Ok(Self { value: VideoIdValue::Bvid(t[0].to_string()) }),
-                None => Err("在输入的字符串中未找到有效的av/bvid".into())
-            }
-        }
-    }
+use rust_bilidown::{
+    AudioPreference,
+    AudioStream,
+    Chapter,
+    chapter_text,
+    Client,
+    Codec,
+    CodecPreference,
+    Container,
+    danmaku,
+    DanmakuFilter,
+    DanmakuFormat,
+    DanmakuStyle,
+    DownloadOptions,
+    Error,
+    extract_video_refs,
+    MuxOptions,
+    OutputTemplate,
+    PageInfo,
+    parse_video_id,
+    QualityPolicy,
+    REG_AVID,
+    REG_BVID,
+    REG_SHORT_URL,
+    REG_URL,
+    StreamInfo,
+    SubtitleFile,
+    SubtitleFormat,
+    TemplateFields,
+    UserState,
+    VideoId,
+    VideoStream,
+    VideoInfo,
+    disambiguate,
+    format_date,
+};
+
+// 退出码，供脚本判断执行结果（参数错误时clap会以2退出）
+const EXIT_PARTIAL_FAILURE: u8 = 1;
+const EXIT_USER_INFO_FAILED: u8 = 3;
+const EXIT_VIDEO_INVALID: u8 = 4;
+const EXIT_NETWORK_ERROR: u8 = 5;
+
+/// B站视频下载器，不带任何参数运行时进入交互模式
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// 视频链接、b23.tv短链接、BV号或av号，也可以是含有多个视频的分享文案，此时逐个下载
+    #[arg(required_unless_present = "input", conflicts_with = "input")]
+    video: Option<String>,
+    /// 批量下载，从文件中读取视频列表，每行一个链接、BV号或av号（也可以是分享文案），#开头的行为注释，为“-”时从标准输入读取
+    #[arg(short, long)]
+    input: Option<PathBuf>,
+    /// Cookie中的SESSDATA，不填则以未登录状态下载
+    #[arg(short, long, default_value = "")]
+    cookie: String,
+    /// 要下载的分P，如“1,3,5-7”，不填则下载链接中p参数指定的分P，链接中没有时下载全部分P
+    #[arg(short, long)]
+    pages: Option<String>,
+    /// 最高清晰度代码（如80为1080P、116为1080P60、120为4K），分P不提供时自动选择较低的，不填则下载最高清晰度
+    #[arg(short, long, conflicts_with_all = ["max_height", "closest_height"])]
+    quality: Option<i32>,
+    /// 最高分辨率（如1080，按短边计算，竖屏视频同样适用），分P不提供时自动选择较低的
+    #[arg(long, conflicts_with = "closest_height")]
+    max_height: Option<u32>,
+    /// 选择分辨率最接近该值（如1080，按短边计算）的清晰度
+    #[arg(long)]
+    closest_height: Option<u32>,
+    /// 保存目录
+    #[arg(short, long, default_value = ".")]
+    output: PathBuf,
+    /// 文件名模板，可用字段有{title}、{bvid}、{aid}、{p}、{part}、{uploader}、{quality}、{codec}、{pubdate}，
+    /// 可以用“/”分隔目录，不填时单P视频为“{title}”，多P视频为“{title}/P{p} {part}”
+    #[arg(short, long, value_parser = parse_template)]
+    template: Option<OutputTemplate>,
+    /// 每个流同时使用的下载连接数
+    #[arg(short = 'j', long, default_value_t = DownloadOptions::default().connections)]
+    connections: u32,
+    /// 单个连接的最低下载速度（KB/s），低于该速度时切换到备用镜像，为0时不切换
+    #[arg(long, default_value_t = DownloadOptions::default().min_speed / 1024)]
+    min_speed: u64,
+    /// 视频编码偏好，如“hevc,avc”表示优先HEVC、其次AVC，“av1”表示只要AV1，不填则不限
+    #[arg(long, default_value = "", value_parser = parse_codecs)]
+    codec: CodecPreference,
+    /// 输出格式，mp4或mkv，mp4不支持Hi-Res无损音频，选到无损音频时自动改为mkv
+    #[arg(short, long, default_value = "mp4", value_parser = parse_container)]
+    format: Container,
+    /// 音质偏好，best、aac、flac（Hi-Res无损）、dolby（杜比全景声）或音质代码（如30280为192K），分P不提供时选择最高普通音质
+    #[arg(long, default_value = "best", value_parser = parse_audio)]
+    audio: AudioPreference,
+    /// 下载字幕并转换为指定格式（srt、ass或vtt）保存在视频旁边，AI字幕需要登录
+    #[arg(short, long, value_parser = parse_subtitle)]
+    subtitle: Option<SubtitleFormat>,
+    /// 同时把字幕作为软字幕内嵌到视频中，仅mkv支持
+    #[arg(long, requires = "subtitle")]
+    embed_subtitles: bool,
+    /// 下载弹幕并保存为指定格式，xml为B站原始格式，ass可作为字幕叠加在视频上，可以用逗号分隔同时保存多种格式
+    #[arg(short, long, value_delimiter = ',', value_parser = parse_danmaku)]
+    danmaku: Vec<DanmakuFormat>,
+    /// 标准字号弹幕在1080P画面中的字号
+    #[arg(long, default_value_t = DanmakuStyle::default().font_size)]
+    danmaku_font_size: u32,
+    /// 弹幕不透明度，0到1
+    #[arg(long, default_value_t = DanmakuStyle::default().opacity)]
+    danmaku_opacity: f64,
+    /// 滚动弹幕划过画面的时间（秒）
+    #[arg(long, default_value_t = DanmakuStyle::default().scroll_duration)]
+    danmaku_duration: f64,
+    /// 弹幕可以占用的画面高度比例，0到1
+    #[arg(long, default_value_t = DanmakuStyle::default().area)]
+    danmaku_area: f64,
+    /// 同屏最多显示的弹幕数，为0时不限制
+    #[arg(long, default_value_t = DanmakuStyle::default().max_count)]
+    danmaku_max_count: usize,
+    /// 弹幕过滤规则文件，每行一条规则（keyword、regex、type或dedupe加规则内容），保存弹幕前过滤掉匹配的弹幕
+    #[arg(long, requires = "danmaku", value_parser = parse_danmaku_filter)]
+    danmaku_filter: Option<DanmakuFilter>,
+    /// 下载封面保存在视频旁边，并内嵌到输出文件中
+    #[arg(long)]
+    cover: bool,
+    /// 使用分P首帧作为封面，分P没有首帧时使用视频封面
+    #[arg(long, requires = "cover")]
+    first_frame_cover: bool,
+    /// 把视频看点写入输出文件的章节，并另存为章节文件
+    #[arg(long)]
+    chapters: bool,
+    /// 只下载音频，按音频编码保存为m4a、flac或eac3，此时忽略清晰度、编码和输出格式
+    #[arg(short, long)]
+    audio_only: bool,
+    /// 从指定时间点开始截取，如“90”、“1:30”、“1:02:03”，不重新编码，实际起点为此后的第一个关键帧；外挂的字幕、弹幕和章节文件仍按完整视频的时间轴
+    #[arg(long, value_parser = parse_time)]
+    start: Option<f64>,
+}
+
+// 清晰度选择方式，Manual为每个分P分别询问
+enum QualityChoice {
+    Manual,
+    Policy(QualityPolicy),
 }
 
-struct PageInfo {
-    cid: u32,
-    p: u32,
-    title: String,
+// 音质选择方式，Manual为每个分P分别询问
+enum AudioChoice {
+    Manual,
+    Preference(AudioPreference),
 }
 
-impl fmt::Display for PageInfo {
+// 封面来源
+#[derive(Clone, Copy)]
+enum CoverSource {
+    Video,
+    FirstFrame,
+}
+
+impl fmt::Display for CoverSource {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "P{}: {}", self.p, self.title)
+        match self {
+            CoverSource::Video => write!(f, "视频封面"),
+            CoverSource::FirstFrame => write!(f, "分P首帧（分P没有首帧时使用视频封面）"),
+        }
     }
 }
 
-struct VideoInfo {
-    bvid: String,
-    title: String,
-    uploader: String,
-    pages: Vec<PageInfo>,
+// 下载设置，命令行模式和交互模式各自收集好之后统一交给download_pages
+struct Settings {
+    quality: QualityChoice,
+    audio: AudioChoice,
+    codecs: CodecPreference,
+    save_dir: PathBuf,
+    // 为None时按分P数量使用默认模板
+    template: Option<OutputTemplate>,
+    container: Container,
+    subtitle: Option<SubtitleFormat>,
+    embed_subtitles: bool,
+    danmaku: Vec<DanmakuFormat>,
+    danmaku_style: DanmakuStyle,
+    danmaku_filter: Option<DanmakuFilter>,
+    cover: Option<CoverSource>,
+    chapters: bool,
+    audio_only: bool,
+    // 截取的起点（秒），应用到所有分P
+    start: Option<f64>,
 }
 
-enum UserState {
-    Vip(String),
-    User(String),
-    None,
+// 主函数，带参数运行时走命令行模式，否则走交互模式
+fn main() -> ExitCode {
+    if std::env::args_os().len() > 1 {
+        run_cli(Cli::parse())
+    } else {
+        run_interactive()
+    }
 }
 
-struct UserInfo {
-    state: UserState,
-    img_url: String,
-    sub_url: String,
+// 命令行模式，全程不询问用户，出错时以对应退出码结束
+fn run_cli(cli: Cli) -> ExitCode {
+    let mut client = match Client::new(&cli.cookie) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("{}{}", "获取用户信息失败，".red(), e.to_string().red());
+            return exit_code_of(&e, EXIT_USER_INFO_FAILED);
+        }
+    };
+    print_user_state(&client);
+    client.set_download_options(DownloadOptions {
+        connections: cli.connections,
+        min_speed: cli.min_speed * 1024,
+    });
+
+    let settings = Settings {
+        quality: QualityChoice::Policy(match (cli.quality, cli.max_height, cli.closest_height) {
+            (Some(t), _, _) => QualityPolicy::MaxQuality(t),
+            (_, Some(t), _) => QualityPolicy::MaxHeight(t),
+            (_, _, Some(t)) => QualityPolicy::ClosestHeight(t),
+            _ => QualityPolicy::Best,
+        }),
+        audio: AudioChoice::Preference(cli.audio),
+        codecs: cli.codec,
+        save_dir: cli.output,
+        template: cli.template,
+        container: cli.format,
+        subtitle: cli.subtitle,
+        embed_subtitles: cli.embed_subtitles,
+        danmaku: cli.danmaku,
+        danmaku_style: DanmakuStyle {
+            font_size: cli.danmaku_font_size,
+            opacity: cli.danmaku_opacity,
+            scroll_duration: cli.danmaku_duration,
+            area: cli.danmaku_area,
+            max_count: cli.danmaku_max_count,
+            ..DanmakuStyle::default()
+        },
+        danmaku_filter: cli.danmaku_filter,
+        cover: match (cli.cover, cli.first_frame_cover) {
+            (true, true) => Some(CoverSource::FirstFrame),
+            (true, false) => Some(CoverSource::Video),
+            _ => None,
+        },
+        chapters: cli.chapters,
+        audio_only: cli.audio_only,
+        start: cli.start,
+    };
+
+    // 批量模式下逐个处理，单个视频出错不影响后续视频
+    let video = match (cli.input, cli.video) {
+        (Some(t), _) => return run_batch(&client, &t, cli.pages.as_deref(), &settings),
+        (None, Some(t)) => t,
+        (None, None) => unreachable!(),
+    };
+    // 输入中有多个视频时（如粘贴了多条分享文案）按批量模式处理
+    let mut items = extract_video_refs(&video);
+    if items.len() > 1 {
+        return download_batch(&client, &items, cli.pages.as_deref(), &settings, false);
+    }
+    let video = items.pop().unwrap_or(video);
+
+    let (video_id, video_info) = match parse_video_id(&video).and_then(|t| client.get_video_info(&t).map(|v| (t, v))) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("{}", e.to_string().bold().red());
+            return exit_code_of(&e, EXIT_VIDEO_INVALID);
+        }
+    };
+    println!("{}，UP主 {}", video_info.title, video_info.uploader);
+    note_ignored_start(&video_id, &settings, false);
+
+    let pages = match pages_for(cli.pages.as_deref(), &video_id, &video_info) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("{}", e.to_string().bold().red());
+            return ExitCode::from(EXIT_VIDEO_INVALID);
+        }
+    };
+    pages_exit_code(download_pages(&client, &video_info, &pages, &settings, &mut HashSet::new()))
 }
 
-// 主函数，主要处理用户输入和程序整体流程
-fn main() {
+// 交互模式，主要处理用户输入和程序整体流程
+fn run_interactive() -> ExitCode {
     // inquire预验证规则，只按照正则表达式进行匹配判断
     let validator = |input: &str| {
         if Regex::new(REG_AVID).unwrap().is_match(input).unwrap() ||
@@ -126,6 +312,10 @@ fn main() {
 
     // 简单提取常规长url中的av/bv号，用于inquire回显展示，防止长链接换行不美观（av/bv号输入和短链接输入不管）
     let format_to_id = &|input: &_| {
+        let refs = extract_video_refs(input);
+        if refs.len() > 1 {
+            return format!("共{}个视频", refs.len());
+        }
         if Regex::new(REG_URL).unwrap().is_match(input).unwrap() {
             match Regex::new(REG_BVID).unwrap().captures(input).unwrap() {
                 Some(caps) => caps[0].to_string(),
@@ -139,73 +329,128 @@ fn main() {
 
     // 构造inquire请求对象
     let video_inquirer = Text::new("请输入要下载的视频链接或BV/av号")
-        .with_help_message("B站视频链接、b23.tv短连接、BV号或av号均可")
+        .with_help_message("B站视频链接、b23.tv短连接、BV号或av号均可，也可以粘贴含有多个视频的分享文案")
         .with_validator(validator)
         .with_formatter(format_to_id);
 
-    let mut input_invalid = true;
-    let mut video_id = VideoId::new("av0").unwrap();
-    let mut video_info = VideoInfo {
-        bvid: "".into(),
-        title: "".into(),
-        uploader: "".into(),
-        pages: Vec::new(),
-    };
-
-    let cookie = Text::new("请输入Cookie SESSDATA =").prompt().unwrap();
-    let mut headers = header::HeaderMap::new();
-    headers.insert(header::COOKIE, header::HeaderValue::from_str(&*format!("SESSDATA={}", &cookie)).unwrap());
-    let client = req::Client::builder()
-        .user_agent(HTTP_USER_AGENT)
-        .default_headers(headers)
-        .build().unwrap();
-
     // 验证Cookie有效性及获取用户信息
-    let user_info = get_user_info(&client).unwrap();
-    match user_info.state {
-        UserState::None => println!("{}", "Cookie无效，未登录状态".yellow()),
-        UserState::User(ref t) => println!("{}", format!("普通用户：{}，你好~", t).green()),
-        UserState::Vip(ref t) => println!("{}", format!("大会员用户：{}，你好~", t).truecolor(251, 114, 153))
-    }
+    let cookie = Text::new("请输入Cookie SESSDATA =").prompt().unwrap();
+    let client = Client::new(&cookie).unwrap();
+    print_user_state(&client);
 
     // 询问+处理逻辑，当处理出错时（短链接404、长链接格式有误等正则查不出来等错误）循环提示用户重新输入
-    while input_invalid {
+    // 输入中有多个视频时逐个解析，不同写法指向同一视频的按bvid去重，保留原链接以便沿用其中的分P参数
+    let mut videos: Vec<(String, VideoId, VideoInfo)> = Vec::new();
+    while videos.is_empty() {
         let res = video_inquirer.clone().prompt().unwrap();
-        match parse_video_id(&res) {
-            Ok(t) => {
-                video_id = t;
-                input_invalid = false;
-            }
-            Err(e) => println!("{}", e.bold().red())
-        };
-        match get_video_info(&video_id, &client) {
-            Ok(t) => video_info = t,
-            Err(e) => {
-                println!("{}", e.bold().red());
-                input_invalid = true;
+        let mut items = extract_video_refs(&res);
+        if items.is_empty() {
+            items.push(res);
+        }
+        for item in items.iter() {
+            match parse_video_id(item).and_then(|t| client.get_video_info(&t).map(|v| (t, v))) {
+                Ok((_, t)) if videos.iter().any(|v| v.2.bvid == t.bvid) => {}
+                Ok((id, t)) => videos.push((item.clone(), id, t)),
+                Err(e) if items.len() > 1 => println!("{}", format!("{}：{}", item, e).bold().red()),
+                Err(e) => println!("{}", e.to_string().bold().red())
             }
         }
     }
 
-    // 展示视频标题、up主基本信息
-    println!("{}，UP主 {}", video_info.title, video_info.uploader);
-
-    // 判断视频是否有分p，如有，要求用户选择需要下载的分p，支持多选，之后将修改覆盖到video_info里
-    if video_info.pages.len() == 1 {
-        println!("该视频无分P，直接下载 {}", video_info.pages[0].title);
-    } else if video_info.pages.len() >= 2 {
-        let validator = |input: &[ListOption<&PageInfo>]| {
-            if input.len() == 0 {
+    // 有多个视频时要求用户选择需要下载的视频，默认全选，每个视频下载全部分P（链接中带有p参数的只下载该分P）
+    if videos.len() > 1 {
+        let validator = |input: &[ListOption<&&VideoInfo>]| {
+            if input.is_empty() {
                 Ok(Validation::Invalid("至少得选一个视频才能下载啊".into()))
             } else {
                 Ok(Validation::Valid)
             }
         };
-        let res = MultiSelect::new("选择想下载的分集", video_info.pages)
+        let all: Vec<usize> = (0..videos.len()).collect();
+        let selected: Vec<usize> = MultiSelect::new("选择想下载的视频", videos.iter().map(|t| &t.2).collect())
             .with_help_message("使用方向键（↑、↓）来移动光标，按空格（Space）键来选中或取消该项，按回车（Enter）提交选择")
+            .with_default(&all)
             .with_validator(validator)
+            .raw_prompt().unwrap()
+            .into_iter().map(|t| t.index).collect();
+        videos = videos.into_iter().enumerate()
+            .filter(|(i, _)| selected.contains(i))
+            .map(|(_, t)| t)
+            .collect();
+    }
+
+    // 只有一个视频时展示视频标题、up主基本信息
+    // 判断视频是否有分p，如有，要求用户选择需要下载的分p，支持多选，链接中带有p参数时预先选中该分P
+    let mut pages = Vec::new();
+    let mut start = None;
+    if let [(_, video_id, video_info)] = videos.as_slice() {
+        println!("{}，UP主 {}", video_info.title, video_info.uploader);
+        pages = video_info.pages.clone();
+        if pages.len() == 1 {
+            println!("该视频无分P，直接下载 {}", pages[0].title);
+        } else if pages.len() >= 2 {
+            let validator = |input: &[ListOption<&PageInfo>]| {
+                if input.is_empty() {
+                    Ok(Validation::Invalid("至少得选一个视频才能下载啊".into()))
+                } else {
+                    Ok(Validation::Valid)
+                }
+            };
+            let default: Vec<usize> = video_id.page
+                .and_then(|p| pages.iter().position(|t| t.p == p))
+                .into_iter().collect();
+            pages = MultiSelect::new("选择想下载的分集", pages)
+                .with_help_message("使用方向键（↑、↓）来移动光标，按空格（Space）键来选中或取消该项，按回车（Enter）提交选择")
+                .with_default(&default)
+                .with_validator(validator)
+                .prompt().unwrap();
+        }
+
+        // 链接中带有时间点（t参数）时，询问是否从该时间点开始截取
+        if let Some(t) = video_id.start {
+            let message = format!("链接中带有时间点 {}，是否从该时间点开始截取", format_time(t));
+            let clip = Confirm::new(&message)
+                .with_default(false)
+                .with_error_message("无效答案，输入“y”表示“是”或“n”表示“否”")
+                .with_help_message("不重新编码，实际起点为此后的第一个关键帧，应用到所选的所有分P；外挂的字幕、弹幕和章节文件仍按完整视频的时间轴")
+                .prompt().unwrap();
+            if clip {
+                start = Some(t);
+            }
+        }
+    } else {
+        println!("共选择{}个视频，每个视频下载全部分P（链接中带有p参数的只下载该分P）", videos.len());
+    }
+    let page_count = videos.iter().map(|t| t.2.pages.len()).max().unwrap_or(1);
+
+    // 询问是否只下载音频，只下载音频时不需要再选清晰度、编码和输出格式
+    let audio_only = Confirm::new("是否只下载音频")
+        .with_default(false)
+        .with_error_message("无效答案，输入“y”表示“是”或“n”表示“否”")
+        .with_help_message("杜比全景声保存为eac3，Hi-Res无损保存为flac，其余保存为m4a")
+        .prompt().unwrap();
+    if audio_only {
+        let save_dir = Text::new("请输入保存目录")
+            .with_default(".")
             .prompt().unwrap();
-        video_info.pages = res;
+        let settings = Settings {
+            quality: QualityChoice::Policy(QualityPolicy::Best),
+            audio: prompt_audio_choice(),
+            codecs: CodecPreference::default(),
+            save_dir: PathBuf::from(save_dir),
+            template: prompt_template(page_count, videos.len() > 1),
+            container: Container::Mp4,
+            subtitle: None,
+            embed_subtitles: false,
+            danmaku: Vec::new(),
+            danmaku_style: DanmakuStyle::default(),
+            danmaku_filter: None,
+            cover: prompt_cover(),
+            chapters: prompt_chapters(),
+            audio_only,
+            start,
+        };
+        return download_selected(&client, &videos, &pages, &settings);
     }
 
     // 询问是否要手动选择下载的分辨率
@@ -214,360 +459,698 @@ fn main() {
         .with_error_message("无效答案，输入“y”表示“是”或“n”表示“否”")
         .with_help_message("默认会下载能够下载的最高质量视频（取决于该视频提供的最高规格和是否拥有大会员）")
         .prompt().unwrap();
+    let quality = if choose_quality_manually {
+        // 分P很多时逐个选择太麻烦，可以选一个策略统一应用到所有分P
+        #[derive(Clone, Copy)]
+        struct Choice(Option<QualityPolicy>);
+        impl fmt::Display for Choice {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match self.0 {
+                    Some(t) => write!(f, "{}", t),
+                    None => write!(f, "每个分P分别选择"),
+                }
+            }
+        }
+        let res = Select::new("选择清晰度", vec![
+            Choice(Some(QualityPolicy::ClosestHeight(1080))),
+            Choice(Some(QualityPolicy::MaxHeight(2160))),
+            Choice(Some(QualityPolicy::MaxHeight(1080))),
+            Choice(Some(QualityPolicy::MaxHeight(720))),
+            Choice(Some(QualityPolicy::MaxHeight(480))),
+            Choice(None),
+        ])
+            .with_help_message("所选策略会应用到所有分P，分P不提供所选清晰度时自动选择较低的")
+            .prompt().unwrap();
+        match res.0 {
+            Some(t) => QualityChoice::Policy(t),
+            None => QualityChoice::Manual,
+        }
+    } else {
+        QualityChoice::Policy(QualityPolicy::Best)
+    };
+    let audio = if choose_quality_manually {
+        prompt_audio_choice()
+    } else {
+        AudioChoice::Preference(AudioPreference::Best)
+    };
 
-    let mut url_list = Vec::new();
+    // 询问编码偏好，同一清晰度有多种编码时按偏好选择
+    let codecs = Select::new("选择视频编码偏好", vec![
+        CodecPreference::default(),
+        CodecPreference(vec![Codec::Avc, Codec::Hevc, Codec::Av1]),
+        CodecPreference(vec![Codec::Hevc, Codec::Avc, Codec::Av1]),
+        CodecPreference(vec![Codec::Av1, Codec::Hevc, Codec::Avc]),
+        CodecPreference(vec![Codec::Avc]),
+        CodecPreference(vec![Codec::Hevc]),
+        CodecPreference(vec![Codec::Av1]),
+    ])
+        .with_help_message("AVC兼容性最好，HEVC和AV1体积更小但需要较新的设备才能播放")
+        .prompt().unwrap();
 
-    // 遍历用户选好的分P列表，获取视频流Url并存入url_list
-    for i in video_info.pages.iter() {
-        println!("正在处理P{}: {}", i.p, i.title);
-        match get_stream_url(&video_info.bvid, &i.cid, choose_quality_manually, &user_info, &client) {
-            Ok(t) => url_list.push(t),
-            Err(e) => println!("{}{}", "该分P处理失败，".red(), e.red())
+    let save_dir = Text::new("请输入保存目录")
+        .with_default(".")
+        .prompt().unwrap();
+    let container = Select::new("选择输出格式", vec![Container::Mp4, Container::Mkv])
+        .with_help_message("MP4兼容性更好，但不支持Hi-Res无损音频，选到无损音频的分P会自动改为MKV")
+        .prompt().unwrap();
+
+    // 询问字幕格式，选择MKV时可以把字幕内嵌进视频
+    #[derive(Clone, Copy)]
+    struct SubtitleChoice(Option<SubtitleFormat>);
+    impl fmt::Display for SubtitleChoice {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self.0 {
+                Some(t) => write!(f, "{}", t),
+                None => write!(f, "不下载字幕"),
+            }
         }
     }
+    let subtitle = Select::new("选择字幕格式", vec![
+        SubtitleChoice(None),
+        SubtitleChoice(Some(SubtitleFormat::Srt)),
+        SubtitleChoice(Some(SubtitleFormat::Ass)),
+        SubtitleChoice(Some(SubtitleFormat::Vtt)),
+    ])
+        .with_help_message("字幕保存在视频旁边，AI生成的字幕需要登录才能下载")
+        .prompt().unwrap().0;
+    let embed_subtitles = subtitle.is_some() && container.supports_subtitles() &&
+        Confirm::new("是否把字幕内嵌到视频中")
+            .with_default(true)
+            .with_error_message("无效答案，输入“y”表示“是”或“n”表示“否”")
+            .prompt().unwrap();
 
-    println!("{:?}", url_list)
-}
-
-// 将用户输入的视频url、短链接、av/bv号等统一处理成av/bv号，方便后续请求
-fn parse_video_id(input: &str) -> Result<VideoId, String> {
-    let reg_bvid = Regex::new(REG_BVID).unwrap();
-    let reg_avid = Regex::new(REG_AVID).unwrap();
-    let reg_url = Regex::new(REG_URL).unwrap();
-    let reg_short_url = Regex::new(REG_SHORT_URL).unwrap();
-    let url_to_id = |a: &str| -> Result<VideoId, String> {
-        let processed_url = match reg_url.captures(a).unwrap() {
-            Some(t) => t[0].to_string(),
-            None => return Err("解析视频Url出错".into())
+    // 询问要保存的弹幕格式，可以不选
+    let danmaku = MultiSelect::new("选择要保存的弹幕格式", vec![DanmakuFormat::Ass, DanmakuFormat::Xml])
+        .with_help_message("不选则不下载弹幕，ASS可作为字幕叠加在视频上，XML为B站原始格式，适合存档")
+        .prompt().unwrap();
+    let danmaku_filter = if danmaku.is_empty() {
+        None
+    } else {
+        let validator = |input: &str| {
+            if input.is_empty() {
+                return Ok(Validation::Valid);
+            }
+            match DanmakuFilter::load(Path::new(input)) {
+                Ok(_) => Ok(Validation::Valid),
+                Err(e) => Ok(Validation::Invalid(e.to_string().into())),
+            }
         };
-        VideoId::new(&processed_url)
+        let res = Text::new("请输入弹幕过滤规则文件")
+            .with_help_message("留空则不过滤，规则文件每行一条规则，如“keyword 剧透”、“regex ^前方高能”、“type top”、“dedupe 10”")
+            .with_validator(validator)
+            .prompt().unwrap();
+        if res.is_empty() {
+            None
+        } else {
+            DanmakuFilter::load(Path::new(&res)).ok()
+        }
     };
-    if reg_url.is_match(input).unwrap() {
-        url_to_id(input)
-    } else if reg_bvid.is_match(input).unwrap() || reg_avid.is_match(input).unwrap() {
-        VideoId::new(input)
-    } else if reg_short_url.is_match(input).unwrap() {
-        let processed_short_url = match reg_short_url.captures(input).unwrap() {
-            Some(t) => t[0].to_string(),
-            None => return Err("解析短Url出错".into())
-        };
-        match parse_short_url(&processed_short_url) {
-            Some(t) => url_to_id(&t),
-            None => Err("该b23.tv短链接无效".into())
+
+    let settings = Settings {
+        quality,
+        audio,
+        codecs,
+        save_dir: PathBuf::from(save_dir),
+        template: prompt_template(page_count, videos.len() > 1),
+        container,
+        subtitle,
+        embed_subtitles,
+        danmaku,
+        danmaku_style: DanmakuStyle::default(),
+        danmaku_filter,
+        cover: prompt_cover(),
+        chapters: prompt_chapters(),
+        audio_only,
+        start,
+    };
+    download_selected(&client, &videos, &pages, &settings)
+}
+
+// 交互模式下载选好的视频，只有一个视频时下载选好的分P，多个视频时按批量模式下载
+fn download_selected(client: &Client, videos: &[(String, VideoId, VideoInfo)], pages: &[PageInfo], settings: &Settings) -> ExitCode {
+    match videos {
+        [(_, _, video_info)] => pages_exit_code(download_pages(client, video_info, pages, settings, &mut HashSet::new())),
+        _ => {
+            let items: Vec<String> = videos.iter().map(|t| t.0.clone()).collect();
+            download_batch(client, &items, None, settings, true)
         }
-    } else {
-        Err("视频链接无效".into())
     }
 }
 
-// 解析b23.tv短链接
-fn parse_short_url(short_url: &str) -> Option<String> {
-    let client = req::Client::builder()
-        .redirect(Policy::none())
-        .build()
-        .unwrap();
-    let resp = match client.get(short_url).send() {
-        Ok(t) => t,
-        Err(..) => return None
-    };
-    if resp.status().is_redirection() {
-        match resp.headers().get("Location") {
-            Some(t) => Some(t.to_str().unwrap().to_string()),
-            None => None
+// 询问音质选择方式，可以选一个偏好统一应用到所有分P，也可以每个分P分别选择
+fn prompt_audio_choice() -> AudioChoice {
+    #[derive(Clone, Copy)]
+    struct Choice(Option<AudioPreference>);
+    impl fmt::Display for Choice {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self.0 {
+                Some(t) => write!(f, "{}", t),
+                None => write!(f, "每个分P分别选择"),
+            }
         }
-    } else {
-        None
+    }
+    let res = Select::new("选择音质", vec![
+        Choice(Some(AudioPreference::Best)),
+        Choice(Some(AudioPreference::Dolby)),
+        Choice(Some(AudioPreference::Flac)),
+        Choice(Some(AudioPreference::Aac)),
+        Choice(None),
+    ])
+        .with_help_message("杜比全景声和Hi-Res无损需要大会员，分P不提供所选音质时选择最高普通音质")
+        .prompt().unwrap();
+    match res.0 {
+        Some(t) => AudioChoice::Preference(t),
+        None => AudioChoice::Manual,
     }
 }
 
-// 校验Cookie是否有效
-fn get_user_info(client: &req::Client) -> Result<UserInfo, String> {
-    #[derive(Deserialize)]
-    struct WbiImg {
-        img_url: String,
-        sub_url: String,
+// 询问是否下载封面及封面来源
+fn prompt_cover() -> Option<CoverSource> {
+    #[derive(Clone, Copy)]
+    struct Choice(Option<CoverSource>);
+    impl fmt::Display for Choice {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self.0 {
+                Some(t) => write!(f, "{}", t),
+                None => write!(f, "不下载封面"),
+            }
+        }
     }
-    #[derive(Deserialize)]
-    struct RawData {
-        isLogin: bool,
-        wbi_img: WbiImg,
+    Select::new("选择封面", vec![
+        Choice(None),
+        Choice(Some(CoverSource::Video)),
+        Choice(Some(CoverSource::FirstFrame)),
+    ])
+        .with_help_message("封面保存在视频旁边，并内嵌到输出文件中")
+        .prompt().unwrap().0
+}
+
+// 询问文件名模板，默认值按分P数量决定，多个视频时保持默认值则每个视频按各自的分P数量决定
+fn prompt_template(page_count: usize, multiple: bool) -> Option<OutputTemplate> {
+    let validator = |input: &str| match input.parse::<OutputTemplate>() {
+        Ok(_) => Ok(Validation::Valid),
+        Err(e) => Ok(Validation::Invalid(e.to_string().into())),
+    };
+    let default = OutputTemplate::default_for(page_count).to_string();
+    let res = Text::new("请输入文件名模板")
+        .with_default(&default)
+        .with_help_message("可用字段：{title} {bvid} {aid} {p} {part} {uploader} {quality} {codec} {pubdate}，用“/”分隔目录")
+        .with_validator(validator)
+        .prompt().unwrap();
+    if multiple && res == default {
+        return None;
     }
-    #[derive(Deserialize)]
-    struct RawResponse {
-        code: i32,
-        data: RawData,
+    res.parse().ok()
+}
+
+// 询问是否写入章节
+fn prompt_chapters() -> bool {
+    Confirm::new("是否写入章节")
+        .with_default(true)
+        .with_error_message("无效答案，输入“y”表示“是”或“n”表示“否”")
+        .with_help_message("把UP主设置的视频看点写入输出文件的章节，并另存为章节文件，没有视频看点的分P不受影响")
+        .prompt().unwrap()
+}
+
+// 网络问题单独使用一个退出码，方便脚本决定是否重试，其余错误使用调用方给定的退出码
+fn exit_code_of(e: &Error, default: u8) -> ExitCode {
+    match e {
+        Error::Network(_) | Error::HttpStatus(_) => ExitCode::from(EXIT_NETWORK_ERROR),
+        _ => ExitCode::from(default),
     }
-    let res = client.get(API_USER_INFO).send();
-    let res = match res {
-        Ok(t) => t,
-        Err(_) => return Err("网络错误".into())
+}
+
+// 展示当前登录状态
+fn print_user_state(client: &Client) {
+    match client.user_info().state {
+        UserState::None => println!("{}", "Cookie无效，未登录状态".yellow()),
+        UserState::User(ref t) => println!("{}", format!("普通用户：{}，你好~", t).green()),
+        UserState::Vip(ref t) => println!("{}", format!("大会员用户：{}，你好~", t).truecolor(251, 114, 153))
+    }
+}
+
+// 按“1,3,5-7”形式的分P表达式筛选分P列表
+fn select_pages(spec: &str, pages: &[PageInfo]) -> Result<Vec<PageInfo>, Error> {
+    // 只记下各段的起止，不展开成列表，“1-4294967295”这样的大范围也不会占用内存
+    let mut ranges = Vec::new();
+    for part in spec.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()) {
+        let (start, end) = match part.split_once('-') {
+            Some((a, b)) => (a.trim().parse::<u32>(), b.trim().parse::<u32>()),
+            None => (part.parse::<u32>(), part.parse::<u32>()),
+        };
+        match (start, end) {
+            (Ok(a), Ok(b)) if a <= b => ranges.push((a, b)),
+            _ => return Err(Error::InvalidInput(format!("分P表达式有误：{}", part)))
+        }
+    }
+    let pages: Vec<PageInfo> = pages.iter()
+        .filter(|t| ranges.iter().any(|(a, b)| (*a..=*b).contains(&t.p)))
+        .cloned()
+        .collect();
+    if pages.is_empty() {
+        return Err(Error::InvalidInput("所选分P在该视频中不存在".into()));
+    }
+    Ok(pages)
+}
+
+// 链接中带有时间点但没有指定截取起点时提示一下，分享链接常常无意带上时间点，所以不自动截取
+// 交互模式只在单个视频时询问是否截取，多个视频一起下载时没有--start可用，提示语不同
+fn note_ignored_start(video_id: &VideoId, settings: &Settings, interactive: bool) {
+    if let (Some(t), None) = (video_id.start, settings.start) {
+        let message = if interactive {
+            format!("已忽略链接中的时间点 {}，多个视频一起下载时不截取", format_time(t))
+        } else {
+            format!("已忽略链接中的时间点 {}，需要从该时间点截取时请使用 --start {}", format_time(t), format_time(t))
+        };
+        println!("{}", message.yellow());
+    }
+}
+
+// 要下载的分P：指定了分P表达式时按表达式筛选，否则链接中带有p参数时只下载该分P，都没有时下载全部分P
+fn pages_for(spec: Option<&str>, video_id: &VideoId, video_info: &VideoInfo) -> Result<Vec<PageInfo>, Error> {
+    match (spec, video_id.page) {
+        (Some(t), _) => select_pages(t, &video_info.pages),
+        (None, Some(p)) => select_pages(&p.to_string(), &video_info.pages),
+        (None, None) => Ok(video_info.pages.clone()),
+    }
+}
+
+// 按清晰度选择方式从分P的视频流信息中选出要下载的视频流
+fn select_video(stream_info: &StreamInfo, quality: &QualityChoice, codecs: &CodecPreference)
+                -> Result<VideoStream, Error> {
+    let video = match quality {
+        QualityChoice::Policy(t) => stream_info.select_video(*t, codecs),
+        QualityChoice::Manual => {
+            let qualities: Vec<_> = stream_info.qualities.iter()
+                .filter(|t| stream_info.video_by_quality(t.id, codecs).is_some())
+                .collect();
+            if qualities.is_empty() {
+                return Err(Error::UnexpectedResponse(format!("该分P没有可下载的清晰度（编码：{}）", codecs)));
+            }
+            let res = Select::new("选择该分P要下载的清晰度", qualities).prompt().unwrap();
+            stream_info.video_by_quality(res.id, codecs)
+        }
     };
-    let res = match res.text() {
-        Ok(t) => t,
-        Err(_) => return Err("响应异常".into())
+    let video = match video {
+        Some(t) => t,
+        None => return Err(Error::UnexpectedResponse(format!("该分P没有可下载的清晰度（编码：{}）", codecs)))
     };
-    let pre_res: RawResponse = match serde_json::from_str(&res) {
-        Ok(t) => t,
-        Err(_) => return Err("响应异常".into())
+    if let Some(t) = stream_info.qualities.iter().find(|t| t.id == video.id) {
+        let codec = Codec::from_id(video.codecid).map(|t| t.to_string()).unwrap_or_else(|| video.codecid.to_string());
+        println!("清晰度：{}，编码：{}", t.description, codec);
+    }
+    Ok(video.clone())
+}
+
+// 按音质选择方式选出要下载的音频流
+fn select_audio(stream_info: &StreamInfo, audio: &AudioChoice) -> Result<AudioStream, Error> {
+    let audio = match audio {
+        AudioChoice::Preference(t) => stream_info.select_audio(*t),
+        AudioChoice::Manual => {
+            let audios = stream_info.audios();
+            if audios.is_empty() {
+                None
+            } else {
+                Some(Select::new("选择该分P要下载的音质", audios).prompt().unwrap())
+            }
+        }
     };
-    if pre_res.code == -101 && !pre_res.data.isLogin {
-        return Ok(UserInfo {
-            state: UserState::None,
-            img_url: pre_res.data.wbi_img.img_url,
-            sub_url: pre_res.data.wbi_img.sub_url,
-        });
-    } else if pre_res.code != 0 || !pre_res.data.isLogin { return Err("无法理解的响应".into()); };
-    #[derive(Deserialize)]
-    struct PostRawData {
-        uname: String,
-        vipStatus: u8,
+    match audio {
+        Some(t) => {
+            println!("音质：{}", t);
+            Ok(t.clone())
+        }
+        None => Err(Error::UnexpectedResponse("该分P没有音频流".into()))
     }
-    #[derive(Deserialize)]
-    struct PostRawResponse {
-        data: PostRawData,
+}
+
+// 所选容器装不下所选音频时改用MKV，并提示想保留原格式时可以改选普通音质
+fn container_for(container: Container, audio: &AudioStream) -> Container {
+    if container.supports_audio(audio.codec()) {
+        return container;
     }
-    let post_res: PostRawResponse = match serde_json::from_str(&res) {
+    println!("{}", format!("{}格式不支持{}音频，该分P改为输出MKV（如需{}格式请选择普通音质）",
+                           container, audio.codec(), container).yellow());
+    Container::Mkv
+}
+
+// 下载分P的全部字幕轨道，转换格式后保存为“文件名.语言代码.扩展名”，返回保存成功的字幕文件
+// 字幕不是必需的，失败时只提示，不影响视频下载
+fn save_subtitles(client: &Client, bvid: &str, cid: u32, output: &Path, format: SubtitleFormat) -> Vec<SubtitleFile> {
+    let tracks = match client.get_subtitles(bvid, cid) {
         Ok(t) => t,
-        Err(_) => return Err("响应异常".into())
+        Err(e) => {
+            println!("{}", format!("获取字幕失败，{}", e).yellow());
+            return Vec::new();
+        }
     };
-    if post_res.data.vipStatus == 0 {
-        Ok(UserInfo {
-            state: UserState::User(post_res.data.uname),
-            img_url: pre_res.data.wbi_img.img_url,
-            sub_url: pre_res.data.wbi_img.sub_url,
-        })
-    } else if post_res.data.vipStatus == 1 {
-        Ok(UserInfo {
-            state: UserState::Vip(post_res.data.uname),
-            img_url: pre_res.data.wbi_img.img_url,
-            sub_url: pre_res.data.wbi_img.sub_url,
-        })
-    } else { Err("用户已登录，但存在无法理解的响应".into()) }
-}
-
-// 获取视频信息，也用于预检视频是否有效
-fn get_video_info(video_id: &VideoId, client: &req::Client) -> Result<VideoInfo, String> {
-    #[derive(Deserialize)]
-    struct RawOwner {
-        name: String,
-    }
-    #[derive(Deserialize)]
-    struct RawPage {
-        cid: u32,
-        page: u32,
-        part: String,
-    }
-    #[derive(Deserialize)]
-    struct RawInfo {
-        bvid: String,
-        title: String,
-        owner: RawOwner,
-        pages: Vec<RawPage>,
-    }
-    #[derive(Deserialize)]
-    struct RawResponse {
-        code: i32,
-        data: RawInfo,
-    }
-    let res = client.get(API_VIDEO_INFO)
-        .query(&[(video_id.get_key(), video_id.to_string())])
-        .send();
-    let res = match res {
-        Ok(t) => t,
-        Err(_) => return Err("网络错误".into())
+    // 未登录时接口不返回AI字幕，列表中没有AI字幕时提示一下
+    let ai_hidden = matches!(client.user_info().state, UserState::None) && !tracks.iter().any(|t| t.is_ai());
+    if tracks.is_empty() {
+        if ai_hidden {
+            println!("{}", "该分P没有字幕（AI字幕需要登录才能下载）".yellow());
+        } else {
+            println!("{}", "该分P没有字幕".yellow());
+        }
+        return Vec::new();
+    }
+    if ai_hidden {
+        println!("{}", "未登录，只下载人工字幕（AI字幕需要登录才能下载）".yellow());
+    }
+    let mut files = Vec::new();
+    for i in tracks.iter() {
+        let mut path = OsString::from(output.as_os_str());
+        path.push(format!(".{}.{}", i.lan, format.extension()));
+        let path = PathBuf::from(path);
+        let res = client.get_subtitle(i).and_then(|t| {
+            if let Some(t) = path.parent() {
+                fs::create_dir_all(t)?;
+            }
+            fs::write(&path, t.render(format))?;
+            Ok(())
+        });
+        match res {
+            Ok(_) => {
+                println!("已保存字幕 {}", i);
+                files.push(SubtitleFile { path, title: i.lan_doc.clone() });
+            }
+            Err(e) => println!("{}", format!("字幕{}保存失败，{}", i, e).yellow()),
+        }
+    }
+    files
+}
+
+// 通过分段弹幕接口下载分P的全部弹幕，保存为“文件名.danmaku.扩展名”
+// 设置了过滤规则时先过滤，再生成XML或ASS
+// 和字幕一样，失败时只提示，不影响视频下载
+fn save_danmaku(client: &Client, page: &PageInfo, output: &Path, format: DanmakuFormat, settings: &Settings) {
+    let mut path = OsString::from(output.as_os_str());
+    path.push(format!(".danmaku.{}", format.extension()));
+    let path = PathBuf::from(path);
+    // 过滤掉的弹幕数
+    let mut filtered = 0;
+    let res = client.get_danmaku(page.cid, page.duration).map(|t| {
+        let t = match settings.danmaku_filter {
+            Some(ref f) => {
+                let total = t.len();
+                let res = f.apply(t);
+                filtered = total - res.len();
+                res
+            }
+            None => t,
+        };
+        match format {
+            DanmakuFormat::Xml => danmaku::to_xml(&t, page.cid),
+            DanmakuFormat::Ass => danmaku::to_ass(&t, &settings.danmaku_style),
+        }
+    }).and_then(|t| {
+        if let Some(t) = path.parent() {
+            fs::create_dir_all(t)?;
+        }
+        fs::write(&path, t)?;
+        Ok(())
+    });
+    match res {
+        Ok(_) if filtered > 0 => println!("已保存{}弹幕，过滤掉{}条", format, filtered),
+        Ok(_) => println!("已保存{}弹幕", format),
+        Err(e) => println!("{}", format!("{}弹幕保存失败，{}", format, e).yellow()),
+    }
+}
+
+// 下载分P的封面并保存为“文件名.jpg”（PNG封面为“文件名.png”），返回保存的路径，失败时只提示
+fn save_cover(client: &Client, video_info: &VideoInfo, page: &PageInfo, output: &Path, source: CoverSource) -> Option<PathBuf> {
+    let url = match source {
+        CoverSource::FirstFrame => page.first_frame.as_ref().unwrap_or(&video_info.cover),
+        CoverSource::Video => &video_info.cover,
     };
-    let res: RawResponse = match res.json() {
+    let mut path = OsString::from(output.as_os_str());
+    path.push(if url.to_lowercase().ends_with(".png") { ".png" } else { ".jpg" });
+    let path = PathBuf::from(path);
+    match client.download_image(url, &path) {
+        Ok(_) => {
+            println!("已保存封面");
+            Some(path)
+        }
+        Err(e) => {
+            println!("{}", format!("封面保存失败，{}", e).yellow());
+            None
+        }
+    }
+}
+
+// 获取分P的视频看点并保存为“文件名.chapters.txt”，返回章节列表，失败时只提示
+fn save_chapters(client: &Client, bvid: &str, cid: u32, output: &Path) -> Vec<Chapter> {
+    let chapters = match client.get_chapters(bvid, cid) {
         Ok(t) => t,
-        Err(_) => return Err("响应异常，视频可能不存在".into())
+        Err(e) => {
+            println!("{}", format!("获取视频看点失败，{}", e).yellow());
+            return Vec::new();
+        }
     };
-    if res.code != 0 { return Err(format!("视频状态异常：{}", res.code)); }
-    let mut pages: Vec<PageInfo> = Vec::new();
-    for i in res.data.pages.iter() {
-        pages.push(PageInfo {
-            title: String::from(&i.part),
-            cid: i.cid,
-            p: i.page,
-        })
+    if chapters.is_empty() {
+        return chapters;
+    }
+    let mut path = OsString::from(output.as_os_str());
+    path.push(".chapters.txt");
+    let path = PathBuf::from(path);
+    let res = path.parent().map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&path, chapter_text(&chapters)));
+    match res {
+        Ok(_) => println!("已保存{}个章节", chapters.len()),
+        Err(e) => println!("{}", format!("章节文件保存失败，{}", e).yellow()),
     }
-    Ok(VideoInfo {
-        bvid: res.data.bvid,
-        title: res.data.title,
-        uploader: res.data.owner.name,
-        pages,
-    })
+    chapters
 }
 
-// 计算B站Wbi签名，见 https://socialsisteryi.github.io/bilibili-API-collect/docs/misc/sign/wbi.html
-fn wbi_sign_para(mut paras: Vec<(String, String)>, img_url: &str, sub_url: &str) -> Result<Vec<(String, String)>, String> {
-    let reg_wbi = Regex::new(REG_WBI_KEY).unwrap();
-    let img_key = match reg_wbi.captures(img_url).unwrap() {
-        Some(t) => t[0].to_string(),
-        None => return Err("参数不合法".into())
-    };
-    let sub_key = match reg_wbi.captures(sub_url).unwrap() {
-        Some(t) => t[0].to_string(),
-        None => return Err("参数不合法".into())
-    };
-    let key = format!("{}{}", img_key, sub_key);
-    let mut mixin_key = String::new();
-    let mut buffer = [0; 4];
-    for i in WBI_KEY_TAB.iter() {
-        mixin_key = mixin_key.add(key.chars().nth(*i as usize).unwrap().encode_utf8(&mut buffer))
-    };
-    let mixin_key = &mixin_key[0..32];
-    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
-    paras.push(("wts".into(), now.to_string()));
-    paras.sort_unstable_by(|a, b| a.0.cmp(&b.0));
-    let mut query = Vec::new();
-    let reg_filter = Regex::new(r"[!'()*]").unwrap();
-    for i in paras.iter() {
-        let val = reg_filter.replace(&i.1, "").to_string();
-        let key = urlencoding::encode(&i.0).to_string().to_lowercase();
-        let val = urlencoding::encode(&val).to_string().to_lowercase();
-        query.push(format!("{}={}", key, val));
+// 下载一个分P：先选好音视频流，按文件名模板确定输出路径，再保存字幕、弹幕等附属文件，最后下载并合并
+fn download_page(client: &Client, video_info: &VideoInfo, page: &PageInfo, settings: &Settings,
+                 used: &mut HashSet<String>) -> Result<PathBuf, Error> {
+    let stream_info = client.get_stream_url(&video_info.bvid, page.cid)?;
+    let video = if settings.audio_only {
+        None
+    } else {
+        Some(select_video(&stream_info, &settings.quality, &settings.codecs)?)
     };
-    let query = query.join("&");
-    let wbi_sign = format!("{:x}", md5::compute(query.add(&mixin_key)));
-    paras.push(("w_rid".into(), wbi_sign));
-    Ok(paras)
-}
-
-// 获取视频流下载链接
-fn get_stream_url(bvid: &str, cid: &u32, choose_quality_manually: bool,
-                  user_info: &UserInfo, client: &req::Client) -> Result<(String, String), String> {
-    let mut quality_flag = match user_info.state {
-        UserState::None => vec![("qn".to_string(), "64".to_string()), ("fnval".to_string(), "16".to_string())],
-        UserState::User(_) => vec![("qn".to_string(), "80".to_string()), ("fnval".to_string(), "16".to_string())],
-        UserState::Vip(_) => vec![("qn".to_string(), "127".to_string()), ("fnval".to_string(), "4048".to_string()),
-                                  ("fourk".to_string(), "1".to_string()),
-        ]
+    let audio = select_audio(&stream_info, &settings.audio)?;
+
+    // 只下载音频时，清晰度和编码字段填音质和音频编码
+    let (quality, codec) = match video {
+        Some(ref t) => (
+            stream_info.qualities.iter().find(|q| q.id == t.id).map(|q| q.description.clone()).unwrap_or_else(|| t.id.to_string()),
+            Codec::from_id(t.codecid).map(|c| c.to_string()).unwrap_or_else(|| t.codecid.to_string()),
+        ),
+        None => (audio.name().to_string(), audio.codec().to_string()),
     };
-    let mut paras = vec![("bvid".to_string(), bvid.to_string()),
-                         ("cid".to_string(), cid.to_string())];
-    paras.append(&mut quality_flag);
-    let paras = wbi_sign_para(paras, &user_info.img_url, &user_info.sub_url)?;
-    let res = match client.get(API_STREAM_URL).query(&paras).send() {
-        Ok(t) => t,
-        Err(_) => return Err("网络错误".into())
+    let template = settings.template.clone().unwrap_or_else(|| OutputTemplate::default_for(video_info.pages.len()));
+    let output = settings.save_dir.join(template.render(&TemplateFields {
+        title: &video_info.title,
+        bvid: &video_info.bvid,
+        aid: video_info.aid,
+        p: page.p,
+        part: &page.title,
+        uploader: &video_info.uploader,
+        quality: &quality,
+        codec: &codec,
+        pubdate: &format_date(video_info.pubdate),
+    }));
+    let output = disambiguate(output, used);
+
+    let subtitles = match settings.subtitle {
+        Some(t) => save_subtitles(client, &video_info.bvid, page.cid, &output, t),
+        None => Vec::new(),
     };
-    let res = res.text().unwrap();
-    #[derive(Deserialize)]
-    struct RawVideo {
-        id: i32,
-        base_url: String,
-        backup_url: Vec<String>,
-        codecid: i32,
-    }
-    #[derive(Deserialize)]
-    struct RawAudio {
-        id: i32,
-        base_url: String,
-        backup_url: Vec<String>,
-    }
-    #[derive(Deserialize)]
-    struct RawDash {
-        video: Vec<RawVideo>,
-        audio: Vec<RawAudio>,
-        dolby: serde_json::Value,
-        flac: serde_json::Value,
-    }
-    #[derive(Deserialize)]
-    struct RawData {
-        accept_description: Vec<String>,
-        accept_quality: Vec<i32>,
-        dash: RawDash,
-    }
-    #[derive(Deserialize)]
-    struct RawResponse {
-        code: i32,
-        data: RawData,
-    }
-    let post_res: RawResponse = match serde_json::from_str(&res) {
-        Ok(t) => t,
-        Err(_) => return Err("响应异常".into())
+    for t in settings.danmaku.iter() {
+        save_danmaku(client, page, &output, *t, settings);
+    }
+    let cover = settings.cover.and_then(|t| save_cover(client, video_info, page, &output, t));
+    let chapters = if settings.chapters {
+        save_chapters(client, &video_info.bvid, page.cid, &output)
+    } else {
+        Vec::new()
     };
-    let mut quality_id = post_res.data.accept_quality[0];
-    if choose_quality_manually {
-        struct Quality(i32, String);
-        impl fmt::Display for Quality {
-            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{}", self.1) }
-        }
-        let mut qualities: Vec<Quality> = Vec::new();
-        for (i, v) in post_res.data.accept_quality.iter().enumerate() {
-            qualities.push(Quality(v.clone(), post_res.data.accept_description[i].to_string()))
-        }
-        let res = Select::new("选择该分P要下载的清晰度", qualities).prompt().unwrap();
-        quality_id = res.0
-    }
-    let mut best_audio = post_res.data.dash.audio.iter().max_by_key(|i| i.id).unwrap().base_url.to_string();
-    if let json::Value::Object(t) = post_res.data.dash.flac {
-        if let Some(t) = t.get("audio") {
-            if let json::Value::Object(t) = t {
-                if let Some(t) = t.get("base_url") {
-                    if let json::Value::String(t) = t {
-                        if !choose_quality_manually {
-                            best_audio = t.to_string();
-                        }
-                    }
-                }
+
+    let video = match video {
+        Some(t) => t,
+        None => {
+            let mut options = MuxOptions::new(Container::for_audio(audio.codec()));
+            options.metadata = video_info.metadata(page);
+            options.chapters = chapters;
+            options.start = settings.start;
+            if cover.is_some() && options.container.supports_cover() {
+                options.cover = cover;
             }
+            return client.download_audio(&video_info.bvid, page.cid, &audio, &output, &options);
         }
     };
-    if let json::Value::Object(t) = post_res.data.dash.dolby {
-        if let Some(t) = t.get("audio") {
-            if let json::Value::Array(t) = t {
-                if let Some(t) = t.get(0) {
-                    if let json::Value::Object(t) = t {
-                        if let Some(t) = t.get("base_url") {
-                            if let json::Value::String(t) = t {
-                                if !choose_quality_manually {
-                                    best_audio = t.to_string();
-                                }
-                            }
-                        }
-                    }
-                }
+    let mut options = MuxOptions::new(container_for(settings.container, &audio));
+    options.cover = cover;
+    options.metadata = video_info.metadata(page);
+    options.chapters = chapters;
+    options.start = settings.start;
+    if settings.embed_subtitles && !subtitles.is_empty() {
+        if options.container.supports_subtitles() {
+            options.subtitles = subtitles;
+        } else {
+            println!("{}", format!("{}格式不支持内嵌字幕，只保存为外挂字幕", options.container).yellow());
+        }
+    }
+    client.download_video(&video_info.bvid, page.cid, &video, &audio, &output, &options)
+}
+
+fn parse_template(s: &str) -> Result<OutputTemplate, Error> {
+    s.parse()
+}
+
+fn parse_danmaku(s: &str) -> Result<DanmakuFormat, Error> {
+    s.parse()
+}
+
+fn parse_danmaku_filter(s: &str) -> Result<DanmakuFilter, Error> {
+    DanmakuFilter::load(Path::new(s))
+}
+
+fn parse_subtitle(s: &str) -> Result<SubtitleFormat, Error> {
+    s.parse()
+}
+
+fn parse_container(s: &str) -> Result<Container, Error> {
+    s.parse()
+}
+
+fn parse_audio(s: &str) -> Result<AudioPreference, Error> {
+    s.parse()
+}
+
+fn parse_codecs(s: &str) -> Result<CodecPreference, Error> {
+    s.parse()
+}
+
+// 解析“90”、“1:30”、“1:02:03”形式的时间点，单位秒
+fn parse_time(s: &str) -> Result<f64, Error> {
+    let mut res = 0.0;
+    for part in s.trim().split(':') {
+        match part.trim().parse::<f64>() {
+            Ok(t) if t >= 0.0 && t.is_finite() => res = res * 60.0 + t,
+            _ => return Err(Error::InvalidInput(format!("时间点格式有误：{}", s)))
+        }
+    }
+    Ok(res)
+}
+
+// 把秒数格式化为“1:30”、“1:02:03”形式，用于提示
+fn format_time(seconds: f64) -> String {
+    let t = seconds.max(0.0) as u64;
+    if t >= 3600 {
+        format!("{}:{:02}:{:02}", t / 3600, t / 60 % 60, t % 60)
+    } else {
+        format!("{}:{:02}", t / 60, t % 60)
+    }
+}
+
+// 遍历选好的分P列表，逐个获取视频流Url并下载，返回处理失败的分P数
+// used为已经用过的输出路径，批量下载时所有视频共用一份，避免标题相同时互相覆盖
+fn download_pages(client: &Client, video_info: &VideoInfo, pages: &[PageInfo], settings: &Settings,
+                  used: &mut HashSet<String>) -> usize {
+    let mut failed = 0;
+    for i in pages.iter() {
+        println!("正在处理P{}: {}", i.p, i.title);
+        match download_page(client, video_info, i, settings, used) {
+            Ok(t) => println!("{}", format!("已保存到 {}", t.display()).green()),
+            Err(e) => {
+                println!("{}{}", "该分P处理失败，".red(), e.to_string().red());
+                failed += 1;
             }
         }
-    };
-    let video_url: Vec<_> = post_res.data.dash.video.iter().filter(|x| x.id == quality_id).collect();
-    Ok((video_url[0].base_url.to_string(), best_audio))
+    }
+    if failed > 0 {
+        println!("{}", format!("共{}个分P处理失败", failed).red());
+    }
+    failed
 }
 
-fn download_video(urls: &(String, String), save_path: &str, client: &mut Client) -> Result<(), String> {
-    extern crate ffmpeg_next as ffmpeg;
-    let mut temp_dir = std::env::temp_dir();
-    temp_dir.push("rust_bilidown");
-    if let Err(_) = fs::create_dir_all(temp_dir.clone()) {
-        return Err("创建目录失败".to_string());
+fn pages_exit_code(failed: usize) -> ExitCode {
+    if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(EXIT_PARTIAL_FAILURE)
     }
-    let mut dests = [String::new(), String::new()];
-    for (i, v) in [&urls.0, &urls.1].iter().enumerate() {
-        let res = client.get(v.to_string()).header(header::REFERER, HTTP_REFERER).send();
-        let res = match res {
+}
+
+// 批量模式，逐个下载列表中的视频，最后汇总每一项的结果
+fn run_batch(client: &Client, input: &Path, pages: Option<&str>, settings: &Settings) -> ExitCode {
+    let items = match read_batch(input) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("{}", format!("读取视频列表失败：{}", e).bold().red());
+            return ExitCode::from(EXIT_VIDEO_INVALID);
+        }
+    };
+    if items.is_empty() {
+        eprintln!("{}", "视频列表为空".bold().red());
+        return ExitCode::from(EXIT_VIDEO_INVALID);
+    }
+    download_batch(client, &items, pages, settings, false)
+}
+
+// 读取视频列表，跳过空行和#开头的注释行，路径为“-”时从标准输入读取
+fn read_batch(input: &Path) -> std::io::Result<Vec<String>> {
+    let text = if input == Path::new("-") {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        fs::read_to_string(input)?
+    };
+    // 每行可以是分享文案，从中找出所有视频；找不到的行原样保留，下载时报错提示
+    Ok(text.lines()
+        .map(|t| t.trim())
+        .filter(|t| !t.is_empty() && !t.starts_with('#'))
+        .flat_map(|t| match extract_video_refs(t) {
+            refs if refs.is_empty() => vec![t.to_string()],
+            refs => refs,
+        })
+        .collect())
+}
+
+// 逐个下载多个视频，单个视频出错时记下原因继续下一个，最后输出汇总
+// 不同写法指向同一视频的（如av号和bv号、短链接）按bvid去重，只下载一次
+fn download_batch(client: &Client, items: &[String], pages: Option<&str>, settings: &Settings, interactive: bool) -> ExitCode {
+    let mut results: Vec<(&String, Result<String, String>)> = Vec::new();
+    let mut seen = HashSet::new();
+    let mut used = HashSet::new();
+    for (n, item) in items.iter().enumerate() {
+        println!("{}", format!("[{}/{}] {}", n + 1, items.len(), item).bold());
+        let (video_id, video_info) = match parse_video_id(item).and_then(|t| client.get_video_info(&t).map(|v| (t, v))) {
             Ok(t) => t,
-            Err(_) => return Err("网络请求错误".to_string())
+            Err(e) => {
+                println!("{}", e.to_string().red());
+                results.push((item, Err(e.to_string())));
+                continue;
+            }
         };
-        let fname = res.url()
-            .path_segments()
-            .and_then(|segments| segments.last())
-            .and_then(|name| if name.is_empty() { None } else { Some(name) })
-            .unwrap_or("tmp0.m4v");
-        println!("file to download: '{}'", fname);
-        let fname = temp_dir.join(fname);
-        println!("will be located under: '{:?}'", fname);
-        let mut dest = match File::create(fname.clone()) {
-            Ok(t) => t,
-            Err(_) => return Err("无法创建文件".to_string())
+        if !seen.insert(video_info.bvid.clone()) {
+            println!("{}", format!("{} 与前面的视频重复，已跳过", video_info.bvid).yellow());
+            continue;
+        }
+        println!("{}，UP主 {}", video_info.title, video_info.uploader);
+        note_ignored_start(&video_id, settings, interactive);
+        let res = pages_for(pages, &video_id, &video_info).map(|pages| (download_pages(client, &video_info, &pages, settings, &mut used), video_info.title.clone()));
+        let res = match res {
+            Ok((0, title)) => Ok(title),
+            Ok((failed, title)) => Err(format!("{}，{}个分P处理失败", title, failed)),
+            Err(e) => Err(e.to_string()),
         };
-        let content = res.text();
-        if let Err(_) = copy(&mut content.unwrap().as_bytes(), &mut dest) {
-            return Err("无法保存文件".to_string());
+        if let Err(ref e) = res {
+            println!("{}", e.red());
         }
-        dests[i] = fname.to_string_lossy().parse().unwrap()
+        results.push((item, res));
     }
-    if let Err(_) = ffmpeg::init() {
-        return Err("ffmpeg异常".to_string());
+
+    let failed = results.iter().filter(|t| t.1.is_err()).count();
+    println!("{}", format!("批量下载完成，成功{}个，失败{}个", results.len() - failed, failed).bold());
+    for (item, res) in results.iter() {
+        match res {
+            Ok(t) => println!("{}", format!("  成功 {} {}", item, t).green()),
+            Err(e) => println!("{}", format!("  失败 {} {}", item, e).red()),
+        }
     }
-    Ok(())
-}
\ No newline at end of file
+    pages_exit_code(failed)
+}
@@ -0,0 +1,336 @@
+extern crate ffmpeg_next as ffmpeg;
+
+use std::fmt;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use ffmpeg::{codec, encoder, format, media, Dictionary, Packet, Rational};
+use ffmpeg::format::stream::Disposition;
+
+use crate::error::{Error, Result};
+use crate::stream::AudioCodec;
+
+// 输出的容器格式，后三种用于只下载音频
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    Mp4,
+    Mkv,
+    M4a,
+    Flac,
+    Eac3,
+}
+
+impl Container {
+    // 只下载音频时按音频编码选择容器
+    pub fn for_audio(codec: AudioCodec) -> Self {
+        match codec {
+            AudioCodec::Aac => Container::M4a,
+            AudioCodec::Flac => Container::Flac,
+            AudioCodec::Eac3 => Container::Eac3,
+        }
+    }
+
+    // 容器能否装下该编码的音频，FLAC放进MP4虽然ffmpeg能写，但很多播放器不认，所以视为不支持
+    // 杜比全景声的E-AC-3在MP4中以ec-3保存，主流播放器都能播放
+    pub fn supports_audio(&self, codec: AudioCodec) -> bool {
+        match self {
+            Container::Mp4 => codec != AudioCodec::Flac,
+            Container::Mkv => true,
+            Container::M4a => codec == AudioCodec::Aac,
+            Container::Flac => codec == AudioCodec::Flac,
+            Container::Eac3 => codec == AudioCodec::Eac3,
+        }
+    }
+
+    // 能否以流复制的方式内嵌SRT/ASS/WebVTT字幕，MP4只支持mov_text，需要转码，这里不支持
+    pub fn supports_subtitles(&self) -> bool {
+        *self == Container::Mkv
+    }
+
+    // 能否内嵌封面，裸E-AC-3流没有地方放封面
+    pub fn supports_cover(&self) -> bool {
+        *self != Container::Eac3
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Mkv => "mkv",
+            Container::M4a => "m4a",
+            Container::Flac => "flac",
+            Container::Eac3 => "eac3",
+        }
+    }
+
+    // ffmpeg中对应的muxer名称
+    fn format_name(&self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Mkv => "matroska",
+            Container::M4a => "ipod",
+            Container::Flac => "flac",
+            Container::Eac3 => "eac3",
+        }
+    }
+}
+
+impl fmt::Display for Container {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Container::Mp4 => write!(f, "MP4"),
+            Container::Mkv => write!(f, "MKV"),
+            Container::M4a => write!(f, "M4A"),
+            Container::Flac => write!(f, "FLAC"),
+            Container::Eac3 => write!(f, "E-AC-3"),
+        }
+    }
+}
+
+// 只解析视频的输出格式，音频格式由for_audio按音频编码决定，不能用来装视频
+impl FromStr for Container {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "mp4" => Ok(Container::Mp4),
+            "mkv" => Ok(Container::Mkv),
+            _ => Err(Error::InvalidInput(format!("不支持的输出格式：{}，可选mp4或mkv", s)))
+        }
+    }
+}
+
+// 要内嵌的字幕文件，title为播放器中显示的轨道名
+#[derive(Clone)]
+pub struct SubtitleFile {
+    pub path: PathBuf,
+    pub title: String,
+}
+
+// 章节，时间单位为秒
+#[derive(Clone)]
+pub struct Chapter {
+    pub start: f64,
+    pub end: f64,
+    pub title: String,
+}
+
+// 把章节转换为OGM格式的章节文件（CHAPTER01=00:00:00.000），mkvmerge和大部分播放器都能识别
+pub fn chapter_text(chapters: &[Chapter]) -> String {
+    let mut res = String::new();
+    for (i, t) in chapters.iter().enumerate() {
+        let ms = (t.start.max(0.0) * 1000.0).round() as u64;
+        let _ = writeln!(res, "CHAPTER{:02}={:02}:{:02}:{:02}.{:03}", i + 1,
+                         ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60, ms % 1000);
+        let _ = writeln!(res, "CHAPTER{:02}NAME={}", i + 1, t.title);
+    }
+    res
+}
+
+// 合并选项，包括输出容器和除音视频轨以外额外写入的内容
+#[derive(Clone)]
+pub struct MuxOptions {
+    pub container: Container,
+    // 内嵌的软字幕，只有MKV支持
+    pub subtitles: Vec<SubtitleFile>,
+    // 封面图片，作为附加图片（attached picture）写入
+    pub cover: Option<PathBuf>,
+    // 文件级的元数据，如标题、作者、日期
+    pub metadata: Vec<(String, String)>,
+    pub chapters: Vec<Chapter>,
+    // 截取的起点（秒），流复制只能从关键帧开始，实际起点为此后的第一个视频关键帧
+    pub start: Option<f64>,
+}
+
+impl MuxOptions {
+    pub fn new(container: Container) -> Self {
+        Self { container, subtitles: Vec::new(), cover: None, metadata: Vec::new(), chapters: Vec::new(), start: None }
+    }
+}
+
+// 输入文件的用途，决定要取其中的哪些流
+enum InputKind<'a> {
+    Media,
+    Subtitle(&'a SubtitleFile),
+    Cover,
+}
+
+// 以流复制的方式把多个输入文件（DASH的视频轨和音频轨）合并到一个容器里，不重新编码
+pub fn mux(inputs: &[&Path], output: &Path, options: &MuxOptions) -> Result<()> {
+    ffmpeg::init()?;
+    ffmpeg::log::set_level(ffmpeg::log::Level::Error);
+    if !options.subtitles.is_empty() && !options.container.supports_subtitles() {
+        return Err(Error::InvalidInput(format!("{}格式不支持内嵌字幕", options.container)));
+    }
+    if options.cover.is_some() && !options.container.supports_cover() {
+        return Err(Error::InvalidInput(format!("{}格式不支持内嵌封面", options.container)));
+    }
+    let cut = match options.start {
+        Some(t) if t > 0.0 => Some(clip_point(inputs, t)?),
+        _ => None,
+    };
+    let (mut ictxs, mut kinds) = (Vec::new(), Vec::new());
+    for i in inputs.iter() {
+        ictxs.push(format::input(i)?);
+        kinds.push(InputKind::Media);
+    }
+    for i in options.subtitles.iter() {
+        ictxs.push(format::input(&i.path)?);
+        kinds.push(InputKind::Subtitle(i));
+    }
+    if let Some(ref t) = options.cover {
+        ictxs.push(format::input(t)?);
+        kinds.push(InputKind::Cover);
+    }
+    let mut octx = format::output_as(output, options.container.format_name())?;
+
+    // mapping[输入序号][输入流序号]为对应的输出流序号，不需要的流为None
+    let mut mapping: Vec<Vec<Option<usize>>> = Vec::new();
+    let mut time_bases: Vec<Vec<Rational>> = Vec::new();
+    let mut ost_count = 0;
+    // 各输出流是否为视频流，截取时视频流要从关键帧开始
+    let mut ost_video = Vec::new();
+    for (ictx, kind) in ictxs.iter().zip(kinds.iter()) {
+        let (mut map, mut tbs) = (Vec::new(), Vec::new());
+        for ist in ictx.streams() {
+            tbs.push(ist.time_base());
+            let medium = ist.parameters().medium();
+            let wanted = match kind {
+                InputKind::Media => medium == media::Type::Video || medium == media::Type::Audio,
+                InputKind::Subtitle(_) => medium == media::Type::Subtitle,
+                // 封面图片只有一个视频流，但只取第一个以防万一
+                InputKind::Cover => medium == media::Type::Video && map.iter().all(|t: &Option<usize>| t.is_none()),
+            };
+            if !wanted {
+                map.push(None);
+                continue;
+            }
+            let mut ost = octx.add_stream(encoder::find(codec::Id::None))?;
+            ost.set_parameters(ist.parameters());
+            // m4s里的codec_tag不一定适用于目标容器，清零后由muxer自己决定
+            unsafe {
+                (*ost.parameters().as_mut_ptr()).codec_tag = 0;
+            }
+            match kind {
+                InputKind::Subtitle(t) => {
+                    let mut metadata = Dictionary::new();
+                    metadata.set("title", &t.title);
+                    ost.set_metadata(metadata);
+                }
+                // 标记为附加图片后，MP4写成covr，MKV写成附件，FLAC写成图片块
+                InputKind::Cover => unsafe {
+                    (*ost.as_mut_ptr()).disposition = Disposition::ATTACHED_PIC.bits();
+                },
+                InputKind::Media => {}
+            }
+            map.push(Some(ost_count));
+            ost_video.push(matches!(kind, InputKind::Media) && medium == media::Type::Video);
+            ost_count += 1;
+        }
+        mapping.push(map);
+        time_bases.push(tbs);
+    }
+    let mut metadata = Dictionary::new();
+    for (k, v) in options.metadata.iter() {
+        metadata.set(k, v);
+    }
+    octx.set_metadata(metadata);
+    // 截取时章节跟着前移，起点之前就结束的章节去掉
+    let offset = cut.unwrap_or(0.0);
+    for (i, t) in options.chapters.iter().filter(|t| t.end > offset).enumerate() {
+        let (start, end) = ((t.start - offset).max(0.0), t.end - offset);
+        octx.add_chapter(i as i64, (1, 1000), (start * 1000.0) as i64, (end * 1000.0) as i64, &t.title)?;
+    }
+    // MP4只把标准键写进iTunes的ilst（©nam、©ART、covr等），bvid等自定义键会被忽略，MKV则全部保留
+    // 不能打开movflags=use_metadata_tags，它会改用mdta写入，导致封面和标准键都丢失
+    octx.write_header()?;
+    // 输出流的time_base在write_header之后才确定
+    let mut ost_time_bases = Vec::new();
+    for i in 0..ost_count {
+        ost_time_bases.push(octx.stream(i).ok_or(Error::Ffmpeg(ffmpeg::Error::StreamNotFound))?.time_base());
+    }
+
+    // 按时间戳交错读取各个输入的包，避免先写完整个视频轨再写音频轨，导致ffmpeg在内存里缓存大量数据
+    let mut iters: Vec<_> = ictxs.iter_mut().map(|t| t.packets()).collect();
+    let mut pending: Vec<Option<(usize, Packet)>> = iters.iter_mut()
+        .map(|t| t.next().map(|(s, p)| (s.index(), p)))
+        .collect();
+    // 截取时各输出流是否已经到达起点，到达之前的包全部丢弃
+    let mut started = vec![false; ost_count];
+    loop {
+        let next = pending.iter().enumerate()
+            .filter_map(|(i, t)| t.as_ref().map(|(s, p)| (i, seconds_of(p, time_bases[i][*s]))))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|t| t.0);
+        let i = match next {
+            Some(t) => t,
+            None => break
+        };
+        let (ist_index, mut packet) = pending[i].take().unwrap();
+        pending[i] = iters[i].next().map(|(s, p)| (s.index(), p));
+        if let Some(ost_index) = mapping[i][ist_index] {
+            // 封面只有一帧，不参与截取
+            if let (Some(cut), false) = (cut, matches!(kinds[i], InputKind::Cover)) {
+                let time_base = time_bases[i][ist_index];
+                if !started[ost_index] {
+                    started[ost_index] = pts_seconds(&packet, time_base) >= cut && (!ost_video[ost_index] || packet.is_key());
+                    if !started[ost_index] {
+                        continue;
+                    }
+                }
+                let offset = (cut / f64::from(time_base)).round() as i64;
+                packet.set_pts(packet.pts().map(|t| t - offset));
+                packet.set_dts(packet.dts().map(|t| t - offset));
+            }
+            packet.rescale_ts(time_bases[i][ist_index], ost_time_bases[ost_index]);
+            packet.set_position(-1);
+            packet.set_stream(ost_index);
+            packet.write_interleaved(&mut octx)?;
+        }
+    }
+    octx.write_trailer()?;
+    Ok(())
+}
+
+fn seconds_of(packet: &Packet, time_base: Rational) -> f64 {
+    packet.dts().or(packet.pts()).unwrap_or(0) as f64 * f64::from(time_base)
+}
+
+// 包的显示时间，关键帧的位置按显示时间判断
+fn pts_seconds(packet: &Packet, time_base: Rational) -> f64 {
+    packet.pts().or(packet.dts()).unwrap_or(0) as f64 * f64::from(time_base)
+}
+
+// 找出截取的实际起点：有视频轨时为不早于start的第一个视频关键帧，只有音频时为不早于start的第一个音频包
+// 需要先读一遍输入，找到后就停，不会读完整个文件
+fn clip_point(inputs: &[&Path], start: f64) -> Result<f64> {
+    let (mut video, mut audio) = (None, None);
+    for i in inputs.iter() {
+        let mut ictx = format::input(i)?;
+        let streams: Vec<(media::Type, Rational)> = ictx.streams()
+            .map(|t| (t.parameters().medium(), t.time_base()))
+            .collect();
+        let has_video = streams.iter().any(|t| t.0 == media::Type::Video);
+        for (ist, packet) in ictx.packets() {
+            let (medium, time_base) = streams[ist.index()];
+            let t = pts_seconds(&packet, time_base);
+            if t < start {
+                continue;
+            }
+            match medium {
+                media::Type::Video if packet.is_key() => {
+                    video.get_or_insert(t);
+                    break;
+                }
+                media::Type::Audio => {
+                    audio.get_or_insert(t);
+                    if !has_video {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    video.or(audio).ok_or_else(|| Error::InvalidInput(format!("截取起点{}秒超出了视频时长", start)))
+}
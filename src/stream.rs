@@ -0,0 +1,435 @@
+use std::cmp::Reverse;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+
+// 清晰度，对应playurl接口中的accept_quality和accept_description
+pub struct Quality {
+    pub id: i32,
+    pub description: String,
+}
+
+impl fmt::Display for Quality {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description)
+    }
+}
+
+// 视频编码，对应playurl接口中的codecid
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Avc,
+    Hevc,
+    Av1,
+}
+
+impl Codec {
+    pub fn from_id(codecid: i32) -> Option<Self> {
+        match codecid {
+            7 => Some(Codec::Avc),
+            12 => Some(Codec::Hevc),
+            13 => Some(Codec::Av1),
+            _ => None
+        }
+    }
+
+    pub fn id(&self) -> i32 {
+        match self {
+            Codec::Avc => 7,
+            Codec::Hevc => 12,
+            Codec::Av1 => 13,
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Codec::Avc => write!(f, "AVC"),
+            Codec::Hevc => write!(f, "HEVC"),
+            Codec::Av1 => write!(f, "AV1"),
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "avc" | "h264" | "h.264" => Ok(Codec::Avc),
+            "hevc" | "h265" | "h.265" => Ok(Codec::Hevc),
+            "av1" => Ok(Codec::Av1),
+            _ => Err(Error::InvalidInput(format!("不支持的视频编码：{}", s)))
+        }
+    }
+}
+
+// 编码偏好，同一清晰度有多种编码时按顺序优先选择，不在列表中的编码不会被选中，列表为空时不限制编码
+#[derive(Clone, Default)]
+pub struct CodecPreference(pub Vec<Codec>);
+
+impl CodecPreference {
+    // 从同一清晰度的多个视频流中按偏好选出一个，不限制编码时取B站返回的第一个
+    pub fn pick<'a>(&self, streams: impl Iterator<Item = &'a VideoStream>) -> Option<&'a VideoStream> {
+        let streams: Vec<_> = streams.collect();
+        if self.0.is_empty() {
+            return streams.first().copied();
+        }
+        self.0.iter().find_map(|c| streams.iter().find(|t| t.codecid == c.id()).copied())
+    }
+}
+
+impl fmt::Display for CodecPreference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0.len() {
+            0 => write!(f, "不限"),
+            1 => write!(f, "仅{}", self.0[0]),
+            _ => write!(f, "{}", self.0.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(" > ")),
+        }
+    }
+}
+
+// 解析“hevc,avc”形式的编码偏好，“any”或空字符串表示不限
+impl FromStr for CodecPreference {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.trim().is_empty() || s.trim().eq_ignore_ascii_case("any") {
+            return Ok(Self::default());
+        }
+        Ok(Self(s.split([',', '>']).map(|t| t.parse()).collect::<Result<_>>()?))
+    }
+}
+
+// 清晰度选择策略，选定一次后应用到所有分P，分P不提供所要求的清晰度时自动退而求其次
+#[derive(Clone, Copy)]
+pub enum QualityPolicy {
+    // 能下载的最高清晰度
+    Best,
+    // 清晰度代码不超过指定值的最高清晰度，都超过时取最低的
+    MaxQuality(i32),
+    // 分辨率短边不超过指定值的最高清晰度，都超过时取最低的
+    MaxHeight(u32),
+    // 分辨率短边最接近指定值的清晰度，有多个时取清晰度代码最高的
+    ClosestHeight(u32),
+}
+
+impl fmt::Display for QualityPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QualityPolicy::Best => write!(f, "最高清晰度"),
+            QualityPolicy::MaxQuality(t) => write!(f, "清晰度代码不超过{}", t),
+            QualityPolicy::MaxHeight(t) => write!(f, "不超过{}P", t),
+            QualityPolicy::ClosestHeight(t) => write!(f, "最接近{}P", t),
+        }
+    }
+}
+
+// 音质选择偏好，所要求的音频流不存在时退而选择码率最高的普通音频
+#[derive(Clone, Copy)]
+pub enum AudioPreference {
+    // 依次优先杜比全景声、Hi-Res无损、码率最高的普通音频
+    Best,
+    // 码率最高的普通音频（AAC）
+    Aac,
+    // Hi-Res无损
+    Flac,
+    // 杜比全景声
+    Dolby,
+    // 指定音质代码（如30280为192K）
+    Id(i32),
+}
+
+impl fmt::Display for AudioPreference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AudioPreference::Best => write!(f, "最高音质"),
+            AudioPreference::Aac => write!(f, "最高普通音质"),
+            AudioPreference::Flac => write!(f, "Hi-Res无损"),
+            AudioPreference::Dolby => write!(f, "杜比全景声"),
+            AudioPreference::Id(t) => write!(f, "音质代码{}", t),
+        }
+    }
+}
+
+impl FromStr for AudioPreference {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "best" => Ok(AudioPreference::Best),
+            "aac" => Ok(AudioPreference::Aac),
+            "flac" | "hires" => Ok(AudioPreference::Flac),
+            "dolby" | "eac3" => Ok(AudioPreference::Dolby),
+            t => t.parse().map(AudioPreference::Id)
+                .map_err(|_| Error::InvalidInput(format!("不支持的音质：{}，可选best、aac、flac、dolby或音质代码", s))),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct VideoStream {
+    pub id: i32,
+    pub codecid: i32,
+    pub width: u32,
+    pub height: u32,
+    pub base_url: String,
+    pub backup_url: Vec<String>,
+}
+
+// 音频编码，普通音频为AAC，Hi-Res无损为FLAC，杜比全景声为E-AC-3
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum AudioCodec {
+    Aac,
+    Flac,
+    Eac3,
+}
+
+impl fmt::Display for AudioCodec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AudioCodec::Aac => write!(f, "AAC"),
+            AudioCodec::Flac => write!(f, "FLAC"),
+            AudioCodec::Eac3 => write!(f, "E-AC-3"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AudioStream {
+    pub id: i32,
+    // 接口返回的编码字符串，如“mp4a.40.2”、“fLaC”、“ec-3”
+    pub codecs: String,
+    // 码率（比特/秒）
+    pub bandwidth: u64,
+    pub base_url: String,
+    pub backup_url: Vec<String>,
+}
+
+impl VideoStream {
+    // 全部候选下载地址，主地址在前，备用镜像在后
+    pub fn urls(&self) -> Vec<String> {
+        [self.base_url.clone()].into_iter().chain(self.backup_url.iter().cloned()).collect()
+    }
+
+    // 分辨率的短边，竖屏视频的1080P为1080x1920，按短边比较才和横屏视频一致
+    pub fn short_side(&self) -> u32 {
+        self.width.min(self.height)
+    }
+}
+
+impl AudioStream {
+    // 全部候选下载地址，主地址在前，备用镜像在后
+    pub fn urls(&self) -> Vec<String> {
+        [self.base_url.clone()].into_iter().chain(self.backup_url.iter().cloned()).collect()
+    }
+
+    // 音频编码，接口没有返回编码字符串时按id判断（30251为Hi-Res无损，30250为杜比全景声）
+    pub fn codec(&self) -> AudioCodec {
+        let codecs = self.codecs.to_lowercase();
+        if codecs.contains("flac") || self.id == 30251 {
+            AudioCodec::Flac
+        } else if codecs.contains("ec-3") || self.id == 30250 {
+            AudioCodec::Eac3
+        } else {
+            AudioCodec::Aac
+        }
+    }
+
+    // 音质名称，如“192K”、“Hi-Res无损”
+    pub fn name(&self) -> &'static str {
+        match (self.id, self.codec()) {
+            (30216, _) => "64K",
+            (30232, _) => "132K",
+            (30280, _) => "192K",
+            (_, AudioCodec::Flac) => "Hi-Res无损",
+            (_, AudioCodec::Eac3) => "杜比全景声",
+            _ => "AAC",
+        }
+    }
+}
+
+impl fmt::Display for AudioStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}（{}kbps）", self.name(), self.bandwidth / 1000)
+    }
+}
+
+// 某个分P的全部DASH流信息
+pub struct StreamInfo {
+    pub qualities: Vec<Quality>,
+    pub video: Vec<VideoStream>,
+    pub audio: Vec<AudioStream>,
+    pub flac: Option<AudioStream>,
+    pub dolby: Option<AudioStream>,
+}
+
+impl StreamInfo {
+    // 按清晰度选择策略选出视频流，只考虑有符合编码偏好的视频流的清晰度
+    pub fn select_video(&self, policy: QualityPolicy, codecs: &CodecPreference) -> Option<&VideoStream> {
+        // 按清晰度从高到低排列的候选视频流
+        let candidates: Vec<&VideoStream> = self.qualities.iter()
+            .filter_map(|t| self.video_by_quality(t.id, codecs))
+            .collect();
+        match policy {
+            QualityPolicy::Best => candidates.first(),
+            QualityPolicy::MaxQuality(q) => candidates.iter().find(|t| t.id <= q).or(candidates.last()),
+            QualityPolicy::MaxHeight(h) => candidates.iter().find(|t| t.short_side() <= h).or(candidates.last()),
+            QualityPolicy::ClosestHeight(h) => candidates.iter().min_by_key(|t| (t.short_side().abs_diff(h), Reverse(t.id))),
+        }.copied()
+    }
+
+    // 按清晰度id查找视频流，同一清晰度有多种编码时按编码偏好选择
+    pub fn video_by_quality(&self, quality_id: i32, codecs: &CodecPreference) -> Option<&VideoStream> {
+        codecs.pick(self.video.iter().filter(|x| x.id == quality_id))
+    }
+
+    // 按id查找音频流，包括Hi-Res无损和杜比全景声
+    pub fn audio_by_id(&self, id: i32) -> Option<&AudioStream> {
+        self.audio.iter().chain(self.flac.iter()).chain(self.dolby.iter()).find(|x| x.id == id)
+    }
+
+    // 选出最佳音频流，hires为true时依次优先杜比全景声、Hi-Res无损，否则只在普通音频中选码率最高的
+    pub fn best_audio(&self, hires: bool) -> Option<&AudioStream> {
+        if hires {
+            if let Some(t) = self.dolby.as_ref().or(self.flac.as_ref()) {
+                return Some(t);
+            }
+        }
+        self.audio.iter().max_by_key(|i| i.id)
+    }
+
+    // 按音质偏好选出音频流，所要求的音频流不存在时选码率最高的普通音频
+    pub fn select_audio(&self, preference: AudioPreference) -> Option<&AudioStream> {
+        match preference {
+            AudioPreference::Best => self.best_audio(true),
+            AudioPreference::Aac => None,
+            AudioPreference::Flac => self.flac.as_ref(),
+            AudioPreference::Dolby => self.dolby.as_ref(),
+            AudioPreference::Id(t) => self.audio_by_id(t),
+        }.or_else(|| self.best_audio(false))
+    }
+
+    // 全部可选的音频流，杜比全景声、Hi-Res无损在前，普通音频按码率从高到低
+    pub fn audios(&self) -> Vec<&AudioStream> {
+        let mut audio: Vec<&AudioStream> = self.audio.iter().collect();
+        audio.sort_by_key(|t| Reverse(t.id));
+        self.dolby.iter().chain(self.flac.iter()).chain(audio).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(id: i32, codec: Codec, width: u32, height: u32) -> VideoStream {
+        VideoStream { id, codecid: codec.id(), width, height, base_url: String::new(), backup_url: Vec::new() }
+    }
+
+    fn audio(id: i32, codecs: &str) -> AudioStream {
+        AudioStream { id, codecs: codecs.to_string(), bandwidth: 0, base_url: String::new(), backup_url: Vec::new() }
+    }
+
+    fn quality(id: i32) -> Quality {
+        Quality { id, description: id.to_string() }
+    }
+
+    // 4K只有HEVC，其余清晰度AVC和HEVC都有，portrait为true时为竖屏视频
+    fn streams(portrait: bool) -> StreamInfo {
+        let size = |w: u32, h: u32| if portrait { (h, w) } else { (w, h) };
+        let mut video_streams = Vec::new();
+        for (id, w, h) in [(120, 3840, 2160), (80, 1920, 1080), (64, 1280, 720), (32, 852, 480), (16, 640, 360)] {
+            let (w, h) = size(w, h);
+            if id != 120 {
+                video_streams.push(video(id, Codec::Avc, w, h));
+            }
+            video_streams.push(video(id, Codec::Hevc, w, h));
+        }
+        StreamInfo {
+            qualities: [120, 80, 64, 32, 16].into_iter().map(quality).collect(),
+            video: video_streams,
+            audio: vec![audio(30216, "mp4a.40.2"), audio(30280, "mp4a.40.2"), audio(30232, "mp4a.40.2")],
+            flac: None,
+            dolby: Some(audio(30250, "ec-3")),
+        }
+    }
+
+    fn selected(info: &StreamInfo, policy: QualityPolicy, codecs: &CodecPreference) -> Option<(i32, i32)> {
+        info.select_video(policy, codecs).map(|t| (t.id, t.codecid))
+    }
+
+    #[test]
+    fn selects_best_quality_with_wanted_codec() {
+        let info = streams(false);
+        let avc = CodecPreference(vec![Codec::Avc]);
+        assert_eq!(selected(&info, QualityPolicy::Best, &CodecPreference::default()), Some((120, 12)));
+        // 4K没有AVC，只要AVC时退到1080P
+        assert_eq!(selected(&info, QualityPolicy::Best, &avc), Some((80, 7)));
+        assert_eq!(selected(&info, QualityPolicy::MaxQuality(70), &avc), Some((64, 7)));
+    }
+
+    #[test]
+    fn falls_back_to_lowest_quality() {
+        let info = streams(false);
+        let codecs = CodecPreference::default();
+        assert_eq!(selected(&info, QualityPolicy::MaxQuality(10), &codecs), Some((16, 7)));
+        assert_eq!(selected(&info, QualityPolicy::MaxHeight(240), &codecs), Some((16, 7)));
+        assert_eq!(selected(&info, QualityPolicy::MaxHeight(1080), &codecs), Some((80, 7)));
+        assert_eq!(selected(&info, QualityPolicy::ClosestHeight(1000), &codecs), Some((80, 7)));
+    }
+
+    #[test]
+    fn compares_short_side_of_portrait_videos() {
+        let info = streams(true);
+        let codecs = CodecPreference::default();
+        assert_eq!(selected(&info, QualityPolicy::MaxHeight(1080), &codecs), Some((80, 7)));
+        assert_eq!(selected(&info, QualityPolicy::MaxHeight(720), &codecs), Some((64, 7)));
+        assert_eq!(selected(&info, QualityPolicy::ClosestHeight(1000), &codecs), Some((80, 7)));
+        assert_eq!(selected(&info, QualityPolicy::ClosestHeight(2000), &codecs), Some((120, 12)));
+        // 与720和480相差一样多时取清晰度代码高的
+        assert_eq!(selected(&info, QualityPolicy::ClosestHeight(600), &codecs), Some((64, 7)));
+    }
+
+    #[test]
+    fn codec_preference_picks_in_order() {
+        let info = streams(false);
+        let pick = |codecs: Vec<Codec>| CodecPreference(codecs)
+            .pick(info.video.iter().filter(|t| t.id == 80))
+            .map(|t| t.codecid);
+        assert_eq!(pick(vec![]), Some(7));
+        assert_eq!(pick(vec![Codec::Hevc, Codec::Avc]), Some(12));
+        assert_eq!(pick(vec![Codec::Av1, Codec::Avc]), Some(7));
+        assert_eq!(pick(vec![Codec::Av1]), None);
+    }
+
+    #[test]
+    fn codec_only_preference_matching_nothing() {
+        let info = streams(false);
+        let av1 = CodecPreference(vec![Codec::Av1]);
+        for policy in [QualityPolicy::Best, QualityPolicy::MaxQuality(10), QualityPolicy::MaxHeight(240), QualityPolicy::ClosestHeight(1080)] {
+            assert_eq!(selected(&info, policy, &av1), None);
+        }
+    }
+
+    #[test]
+    fn selects_audio_by_preference() {
+        let mut info = streams(false);
+        let id = |preference: AudioPreference, info: &StreamInfo| info.select_audio(preference).map(|t| t.id);
+        assert_eq!(id(AudioPreference::Best, &info), Some(30250));
+        assert_eq!(id(AudioPreference::Aac, &info), Some(30280));
+        assert_eq!(id(AudioPreference::Dolby, &info), Some(30250));
+        assert_eq!(id(AudioPreference::Id(30232), &info), Some(30232));
+        // 所要求的音频流不存在时选码率最高的普通音频
+        assert_eq!(id(AudioPreference::Flac, &info), Some(30280));
+        assert_eq!(id(AudioPreference::Id(12345), &info), Some(30280));
+        info.dolby = None;
+        info.flac = Some(audio(30251, "fLaC"));
+        assert_eq!(id(AudioPreference::Best, &info), Some(30251));
+        info.flac = None;
+        assert_eq!(id(AudioPreference::Best, &info), Some(30280));
+        info.audio.clear();
+        assert_eq!(id(AudioPreference::Best, &info), None);
+    }
+}
@@ -0,0 +1,156 @@
+use std::fmt;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+
+// 分P的一条字幕轨道，对应播放器接口中subtitle.subtitles的一项
+#[derive(Clone)]
+pub struct SubtitleTrack {
+    // 语言代码，如“zh-CN”，AI生成的字幕以“ai-”开头
+    pub lan: String,
+    // 语言名称，如“中文（中国）”
+    pub lan_doc: String,
+    pub url: String,
+}
+
+impl SubtitleTrack {
+    pub fn is_ai(&self) -> bool {
+        self.lan.starts_with("ai-")
+    }
+}
+
+impl fmt::Display for SubtitleTrack {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}（{}）", self.lan_doc, self.lan)
+    }
+}
+
+// 字幕中的一句，时间单位为秒
+#[derive(Deserialize)]
+pub struct SubtitleLine {
+    pub from: f64,
+    pub to: f64,
+    pub content: String,
+}
+
+// B站JSON格式的字幕
+#[derive(Deserialize)]
+pub struct Subtitle {
+    pub body: Vec<SubtitleLine>,
+}
+
+// 字幕的输出格式
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SubtitleFormat {
+    Srt,
+    Ass,
+    Vtt,
+}
+
+impl SubtitleFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            SubtitleFormat::Srt => "srt",
+            SubtitleFormat::Ass => "ass",
+            SubtitleFormat::Vtt => "vtt",
+        }
+    }
+}
+
+impl fmt::Display for SubtitleFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SubtitleFormat::Srt => write!(f, "SRT"),
+            SubtitleFormat::Ass => write!(f, "ASS"),
+            SubtitleFormat::Vtt => write!(f, "WebVTT"),
+        }
+    }
+}
+
+impl FromStr for SubtitleFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "srt" => Ok(SubtitleFormat::Srt),
+            "ass" => Ok(SubtitleFormat::Ass),
+            "vtt" | "webvtt" => Ok(SubtitleFormat::Vtt),
+            _ => Err(Error::InvalidInput(format!("不支持的字幕格式：{}", s)))
+        }
+    }
+}
+
+// ASS字幕的文件头，按1080P画面排版，字幕在底部居中
+const ASS_HEADER: &str = "[Script Info]
+ScriptType: v4.00+
+PlayResX: 1920
+PlayResY: 1080
+WrapStyle: 0
+
+[V4+ Styles]
+Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
+Style: Default,Microsoft YaHei,64,&H00FFFFFF,&H00FFFFFF,&H00000000,&H80000000,0,0,0,0,100,100,0,0,1,3,0,2,60,60,50,1
+
+[Events]
+Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+";
+
+impl Subtitle {
+    // 转换为指定格式的字幕文本
+    pub fn render(&self, format: SubtitleFormat) -> String {
+        match format {
+            SubtitleFormat::Srt => self.to_srt(),
+            SubtitleFormat::Ass => self.to_ass(),
+            SubtitleFormat::Vtt => self.to_vtt(),
+        }
+    }
+
+    pub fn to_srt(&self) -> String {
+        let mut res = String::new();
+        for (i, t) in self.body.iter().enumerate() {
+            let _ = write!(res, "{}\n{} --> {}\n{}\n\n", i + 1,
+                           timestamp(t.from, ','), timestamp(t.to, ','), t.content);
+        }
+        res
+    }
+
+    pub fn to_vtt(&self) -> String {
+        let mut res = String::from("WEBVTT\n\n");
+        for t in self.body.iter() {
+            let _ = write!(res, "{} --> {}\n{}\n\n", timestamp(t.from, '.'), timestamp(t.to, '.'), t.content);
+        }
+        res
+    }
+
+    pub fn to_ass(&self) -> String {
+        let mut res = String::from(ASS_HEADER);
+        for t in self.body.iter() {
+            let _ = writeln!(res, "Dialogue: 0,{},{},Default,,0,0,0,,{}",
+                             ass_timestamp(t.from), ass_timestamp(t.to), ass_escape(&t.content));
+        }
+        res
+    }
+}
+
+// SRT和WebVTT的时间戳，如“00:01:02,345”，两者只有毫秒前的分隔符不同
+fn timestamp(seconds: f64, separator: char) -> String {
+    let ms = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!("{:02}:{:02}:{:02}{}{:03}", ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60, separator, ms % 1000)
+}
+
+// ASS的时间戳，精确到百分之一秒，如“0:01:02.35”
+pub(crate) fn ass_timestamp(seconds: f64) -> String {
+    let cs = (seconds.max(0.0) * 100.0).round() as u64;
+    format!("{}:{:02}:{:02}.{:02}", cs / 360_000, cs / 6000 % 60, cs / 100 % 60, cs % 100)
+}
+
+// ASS文本中换行要写成\N，花括号会被当成特效标签
+pub(crate) fn ass_escape(text: &str) -> String {
+    text.replace("\r\n", "\n")
+        .replace('\n', "\\N")
+        .replace('{', "｛")
+        .replace('}', "｝")
+}
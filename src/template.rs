@@ -0,0 +1,341 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+
+// 单P视频的默认文件名模板
+pub const DEFAULT_TEMPLATE: &str = "{title}";
+// 多P视频的默认文件名模板，每个视频一个目录
+pub const DEFAULT_MULTI_PAGE_TEMPLATE: &str = "{title}/P{p} {part}";
+
+// 文件名（不含扩展名）的最大字节数，常见文件系统限制为255字节，留出给“.zh-CN.srt”等后缀的余量
+const MAX_NAME_BYTES: usize = 200;
+
+// Windows的保留设备名，不区分大小写，带扩展名也不行
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// 把任意文本处理成各平台都能用的文件名
+// Windows禁用的字符换成对应的全角字符，去掉控制字符和首尾的空格、末尾的点，避开保留设备名，按字节截断但不截断UTF-8字符
+pub fn sanitize_file_name(name: &str) -> String {
+    let mapped: String = name.chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            '<' => '＜',
+            '>' => '＞',
+            ':' => '：',
+            '"' => '＂',
+            '/' => '／',
+            '\\' => '＼',
+            '|' => '｜',
+            '?' => '？',
+            '*' => '＊',
+            _ => c,
+        })
+        .collect();
+    let mut res = truncate_bytes(mapped.trim(), MAX_NAME_BYTES)
+        .trim_end_matches(['.', ' '])
+        .to_string();
+    let stem = res.split('.').next().unwrap_or("").trim_end();
+    if RESERVED_NAMES.iter().any(|t| t.eq_ignore_ascii_case(stem)) {
+        res.insert(stem.len(), '_');
+    }
+    if res.is_empty() {
+        res.push('_');
+    }
+    res
+}
+
+// 截断到不超过max字节，截断位置落在字符中间时往前退到字符边界
+fn truncate_bytes(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+// 同一次下载中多个分P渲染出相同路径时（如分P标题相同），在后来的文件名后加上“ (2)”、“ (3)”区分
+// 比较时不区分大小写，因为Windows和macOS的文件系统默认不区分
+pub fn disambiguate(path: PathBuf, used: &mut HashSet<String>) -> PathBuf {
+    let key = |t: &PathBuf| t.to_string_lossy().to_lowercase();
+    if used.insert(key(&path)) {
+        return path;
+    }
+    let name = path.file_name().map(|t| t.to_string_lossy().into_owned()).unwrap_or_default();
+    for i in 2.. {
+        let res = path.with_file_name(format!("{} ({})", name, i));
+        if used.insert(key(&res)) {
+            return res;
+        }
+    }
+    unreachable!()
+}
+
+// 模板中可以使用的字段
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Title,
+    Bvid,
+    Aid,
+    P,
+    Part,
+    Uploader,
+    Quality,
+    Codec,
+    Pubdate,
+}
+
+impl FromStr for Field {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "title" => Ok(Field::Title),
+            "bvid" => Ok(Field::Bvid),
+            "aid" => Ok(Field::Aid),
+            "p" => Ok(Field::P),
+            "part" => Ok(Field::Part),
+            "uploader" => Ok(Field::Uploader),
+            "quality" => Ok(Field::Quality),
+            "codec" => Ok(Field::Codec),
+            "pubdate" => Ok(Field::Pubdate),
+            _ => Err(Error::InvalidInput(format!(
+                "未知的文件名模板字段：{{{}}}，可选title、bvid、aid、p、part、uploader、quality、codec、pubdate", s)))
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Segment {
+    Text(String),
+    Field(Field),
+    // 目录分隔符，模板中的“/”或“\”
+    Separator,
+}
+
+// 渲染模板需要的各个字段的值
+pub struct TemplateFields<'a> {
+    pub title: &'a str,
+    pub bvid: &'a str,
+    pub aid: u64,
+    pub p: u32,
+    pub part: &'a str,
+    pub uploader: &'a str,
+    pub quality: &'a str,
+    pub codec: &'a str,
+    pub pubdate: &'a str,
+}
+
+// 输出文件名模板，如“{title}/P{p} {part}”，渲染结果为相对保存目录、不含扩展名的路径
+#[derive(Clone)]
+pub struct OutputTemplate {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl OutputTemplate {
+    // 多P视频和单P视频的默认模板
+    pub fn default_for(page_count: usize) -> Self {
+        let source = if page_count > 1 { DEFAULT_MULTI_PAGE_TEMPLATE } else { DEFAULT_TEMPLATE };
+        source.parse().unwrap()
+    }
+
+    // 每一级目录名和文件名都经过sanitize_file_name处理，字段值里的“/”不会多出一层目录
+    pub fn render(&self, fields: &TemplateFields) -> PathBuf {
+        let mut res = PathBuf::new();
+        let mut component = String::new();
+        for t in self.segments.iter() {
+            match t {
+                Segment::Text(t) => component.push_str(t),
+                Segment::Field(t) => component.push_str(&field_value(*t, fields)),
+                Segment::Separator => {
+                    if !component.trim().is_empty() {
+                        res.push(sanitize_file_name(&component));
+                    }
+                    component.clear();
+                }
+            }
+        }
+        if !component.trim().is_empty() || res.as_os_str().is_empty() {
+            res.push(sanitize_file_name(&component));
+        }
+        res
+    }
+}
+
+fn field_value(field: Field, fields: &TemplateFields) -> String {
+    match field {
+        Field::Title => fields.title.to_string(),
+        Field::Bvid => fields.bvid.to_string(),
+        Field::Aid => fields.aid.to_string(),
+        Field::P => fields.p.to_string(),
+        Field::Part => fields.part.to_string(),
+        Field::Uploader => fields.uploader.to_string(),
+        Field::Quality => fields.quality.to_string(),
+        Field::Codec => fields.codec.to_string(),
+        Field::Pubdate => fields.pubdate.to_string(),
+    }
+}
+
+impl FromStr for OutputTemplate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(t) => name.push(t),
+                            None => return Err(Error::InvalidInput(format!("文件名模板中的花括号没有闭合：{}", s))),
+                        }
+                    }
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    segments.push(Segment::Field(name.trim().parse()?));
+                }
+                '}' => return Err(Error::InvalidInput(format!("文件名模板中有多余的花括号：{}", s))),
+                '/' | '\\' => {
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    segments.push(Segment::Separator);
+                }
+                _ => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+        if !segments.iter().any(|t| matches!(t, Segment::Field(_))) {
+            return Err(Error::InvalidInput("文件名模板中至少要有一个字段，否则所有文件会重名".into()));
+        }
+        Ok(Self { source: s.to_string(), segments })
+    }
+}
+
+impl fmt::Display for OutputTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields<'a>(title: &'a str, uploader: &'a str) -> TemplateFields<'a> {
+        TemplateFields {
+            title,
+            bvid: "BV17x411w7KC",
+            aid: 170001,
+            p: 2,
+            part: "第二集",
+            uploader,
+            quality: "1080P",
+            codec: "AVC",
+            pubdate: "2023-10-01",
+        }
+    }
+
+    fn render(template: &str, fields: &TemplateFields) -> PathBuf {
+        template.parse::<OutputTemplate>().unwrap().render(fields)
+    }
+
+    #[test]
+    fn sanitize_maps_forbidden_characters() {
+        assert_eq!(sanitize_file_name("a<b>c:d\"e/f\\g|h?i*j"), "a＜b＞c：d＂e／f＼g｜h？i＊j");
+        assert_eq!(sanitize_file_name("  标题\t\n换行  "), "标题换行");
+        assert_eq!(sanitize_file_name("结尾的点. . ."), "结尾的点");
+    }
+
+    #[test]
+    fn sanitize_never_returns_empty_or_dot_names() {
+        assert_eq!(sanitize_file_name(""), "_");
+        assert_eq!(sanitize_file_name("   "), "_");
+        assert_eq!(sanitize_file_name("."), "_");
+        assert_eq!(sanitize_file_name(".."), "_");
+        assert_eq!(sanitize_file_name("\u{7}"), "_");
+        assert_eq!(render("{title}", &fields("", "")), PathBuf::from("_"));
+    }
+
+    #[test]
+    fn sanitize_avoids_reserved_names() {
+        assert_eq!(sanitize_file_name("CON"), "CON_");
+        assert_eq!(sanitize_file_name("nul.txt"), "nul_.txt");
+        assert_eq!(sanitize_file_name("aux .x"), "aux_ .x");
+        assert_eq!(sanitize_file_name("Com1.tar.gz"), "Com1_.tar.gz");
+        assert_eq!(sanitize_file_name("console"), "console");
+        assert_eq!(sanitize_file_name("LPT10"), "LPT10");
+    }
+
+    #[test]
+    fn sanitize_truncates_on_char_boundaries() {
+        let res = sanitize_file_name(&"中".repeat(100));
+        assert_eq!(res, "中".repeat(66));
+        let res = sanitize_file_name(&format!("a{}", "中".repeat(100)));
+        assert_eq!(res, format!("a{}", "中".repeat(66)));
+        assert_eq!(sanitize_file_name(&"a".repeat(300)).len(), MAX_NAME_BYTES);
+        assert_eq!(truncate_bytes("中文", 4), "中");
+        assert_eq!(truncate_bytes("中文", 6), "中文");
+        assert_eq!(truncate_bytes("中文", 2), "");
+    }
+
+    #[test]
+    fn disambiguate_appends_counters() {
+        let mut used = HashSet::new();
+        assert_eq!(disambiguate(PathBuf::from("视频/P1 标题"), &mut used), PathBuf::from("视频/P1 标题"));
+        assert_eq!(disambiguate(PathBuf::from("视频/P1 标题"), &mut used), PathBuf::from("视频/P1 标题 (2)"));
+        // 不区分大小写
+        assert_eq!(disambiguate(PathBuf::from("视频/p1 标题"), &mut used), PathBuf::from("视频/p1 标题 (3)"));
+        assert_eq!(disambiguate(PathBuf::from("视频/P2 标题"), &mut used), PathBuf::from("视频/P2 标题"));
+    }
+
+    #[test]
+    fn template_rejects_invalid_sources() {
+        assert!("{title".parse::<OutputTemplate>().is_err());
+        assert!("title}".parse::<OutputTemplate>().is_err());
+        assert!("{name}".parse::<OutputTemplate>().is_err());
+        assert!("固定文件名".parse::<OutputTemplate>().is_err());
+        assert!("".parse::<OutputTemplate>().is_err());
+        assert_eq!("{ title }-{bvid}".parse::<OutputTemplate>().unwrap().to_string(), "{ title }-{bvid}");
+    }
+
+    #[test]
+    fn template_renders_all_fields() {
+        let res = render("{uploader}/{pubdate} {title} [{bvid} av{aid}] P{p} {part} {quality} {codec}", &fields("标题", "UP主"));
+        assert_eq!(res, PathBuf::from("UP主/2023-10-01 标题 [BV17x411w7KC av170001] P2 第二集 1080P AVC"));
+        assert_eq!(OutputTemplate::default_for(1).render(&fields("标题", "")), PathBuf::from("标题"));
+        assert_eq!(OutputTemplate::default_for(3).render(&fields("标题", "")), PathBuf::from("标题/P2 第二集"));
+    }
+
+    #[test]
+    fn template_cannot_escape_save_dir() {
+        assert_eq!(render("../{title}", &fields("标题", "")), PathBuf::from("_/标题"));
+        assert_eq!(render("/{title}", &fields("标题", "")), PathBuf::from("标题"));
+        assert_eq!(render("{title}", &fields("../a/b", "")), PathBuf::from("..／a／b"));
+        assert_eq!(render("{title}", &fields("..", "")), PathBuf::from("_"));
+    }
+
+    #[test]
+    fn template_skips_empty_directories() {
+        assert_eq!(render("{uploader}/{title}", &fields("标题", "")), PathBuf::from("标题"));
+        assert_eq!(render("{uploader}//{title}", &fields("标题", "  ")), PathBuf::from("标题"));
+        assert_eq!(render("{title}/", &fields("标题", "")), PathBuf::from("标题"));
+    }
+}
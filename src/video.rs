@@ -0,0 +1,383 @@
+use std::fmt;
+
+use fancy_regex::Regex;
+use reqwest::{blocking as req, redirect::Policy};
+
+use crate::{BV_ALPHABET, BV_MAX_AID, BV_XOR_CODE, REG_AVID, REG_BVID, REG_SHORT_URL, REG_URL, REG_VIDEO_REF};
+use crate::error::{Error, Result};
+
+pub enum VideoIdValue {
+    Avid(u64),
+    Bvid(String),
+}
+
+pub struct VideoId {
+    pub value: VideoIdValue,
+    // 链接中p参数指定的分P
+    pub page: Option<u32>,
+    // 链接中t参数指定的时间点（秒）
+    pub start: Option<f64>,
+}
+
+impl VideoId {
+    // av号和BV号在这里就完成校验，无效的不会再去请求视频信息
+    // 先找BV号，BV号中可能含有“av”加数字的片段
+    pub fn new(av_or_bvid: &str) -> Result<Self> {
+        match Regex::new(REG_BVID).unwrap().captures(av_or_bvid).unwrap() {
+            Some(t) => bv_to_av(&t[0]).map(|_| Self { value: VideoIdValue::Bvid(t[0].to_string()), page: None, start: None }),
+            None => match Regex::new(REG_AVID).unwrap().captures(av_or_bvid).unwrap() {
+                Some(t) => match t[0][2..].parse::<u64>() {
+                    Ok(t) => av_to_bv(t).map(|_| Self { value: VideoIdValue::Avid(t), page: None, start: None }),
+                    Err(t) => Err(Error::InvalidInput(format!("av号无效：{}", t)))
+                },
+                None => Err(Error::InvalidInput("在输入的字符串中未找到有效的av/bvid".into()))
+            }
+        }
+    }
+
+    // 本地换算出av号，不需要请求接口
+    pub fn aid(&self) -> Result<u64> {
+        match &self.value {
+            VideoIdValue::Avid(t) => Ok(*t),
+            VideoIdValue::Bvid(t) => bv_to_av(t),
+        }
+    }
+
+    // 本地换算出BV号，不需要请求接口
+    pub fn bvid(&self) -> Result<String> {
+        match &self.value {
+            VideoIdValue::Avid(t) => av_to_bv(*t),
+            VideoIdValue::Bvid(t) => bv_to_av(t).map(|_| t.clone()),
+        }
+    }
+}
+
+// 把av号编码为BV号，与B站的算法一致
+pub fn av_to_bv(aid: u64) -> Result<String> {
+    if aid == 0 || aid >= BV_MAX_AID {
+        return Err(Error::InvalidInput(format!("av号超出范围：{}", aid)));
+    }
+    let mut res = *b"BV1000000000";
+    let mut t = (BV_MAX_AID | aid) ^ BV_XOR_CODE;
+    for i in (3..res.len()).rev() {
+        res[i] = BV_ALPHABET[(t % 58) as usize];
+        t /= 58;
+    }
+    res.swap(3, 9);
+    res.swap(4, 7);
+    Ok(String::from_utf8(res.to_vec()).unwrap())
+}
+
+// 把BV号解码为av号，顺带校验BV号：长度、前缀、字符都要合法，且解码出的标志位要对得上，手误或截断的BV号在请求前就能发现
+pub fn bv_to_av(bvid: &str) -> Result<u64> {
+    let invalid = || Error::InvalidInput(format!("BV号无效：{}", bvid));
+    let mut chars = bvid.as_bytes().to_vec();
+    if chars.len() != 12 || !chars.starts_with(b"BV1") {
+        return Err(invalid());
+    }
+    chars.swap(3, 9);
+    chars.swap(4, 7);
+    let mut t: u64 = 0;
+    for c in chars[3..].iter() {
+        let i = BV_ALPHABET.iter().position(|a| a == c).ok_or_else(invalid)?;
+        t = t * 58 + i as u64;
+    }
+    // 编码时固定带上BV_MAX_AID这一位，解码后对不上说明BV号有误
+    if t / BV_MAX_AID != 1 {
+        return Err(invalid());
+    }
+    match (t % BV_MAX_AID) ^ BV_XOR_CODE {
+        0 => Err(invalid()),
+        aid => Ok(aid),
+    }
+}
+
+impl fmt::Display for VideoId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.value {
+            VideoIdValue::Avid(t) => write!(f, "{}", t),
+            VideoIdValue::Bvid(t) => write!(f, "{}", t),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct PageInfo {
+    pub cid: u32,
+    pub p: u32,
+    pub title: String,
+    // 分P时长（秒）
+    pub duration: u32,
+    // 分P首帧图片地址，部分视频没有
+    pub first_frame: Option<String>,
+}
+
+impl fmt::Display for PageInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "P{}: {}", self.p, self.title)
+    }
+}
+
+pub struct VideoInfo {
+    pub bvid: String,
+    pub aid: u64,
+    pub title: String,
+    pub uploader: String,
+    // 封面图片地址
+    pub cover: String,
+    // 简介
+    pub desc: String,
+    // 发布时间（Unix时间戳）
+    pub pubdate: i64,
+    // 分区名称
+    pub tname: String,
+    pub tags: Vec<String>,
+    pub pages: Vec<PageInfo>,
+}
+
+impl VideoInfo {
+    // 分P在网页上的地址
+    pub fn url(&self, page: &PageInfo) -> String {
+        if self.pages.len() > 1 {
+            format!("https://www.bilibili.com/video/{}?p={}", self.bvid, page.p)
+        } else {
+            format!("https://www.bilibili.com/video/{}", self.bvid)
+        }
+    }
+
+    // 写入输出文件的元数据，键名使用ffmpeg的通用键名，播放器和媒体库能直接识别
+    // MP4只保留标准键，所以来源链接、bvid和av号也写进comment
+    pub fn metadata(&self, page: &PageInfo) -> Vec<(String, String)> {
+        let title = if self.pages.len() > 1 {
+            format!("{} P{} {}", self.title, page.p, page.title)
+        } else {
+            self.title.clone()
+        };
+        let mut res = vec![
+            ("title".to_string(), title),
+            ("artist".to_string(), self.uploader.clone()),
+            ("date".to_string(), format_date(self.pubdate)),
+            ("description".to_string(), self.desc.clone()),
+            ("comment".to_string(), format!("{} {} av{}", self.url(page), self.bvid, self.aid)),
+            ("genre".to_string(), self.tname.clone()),
+            ("bvid".to_string(), self.bvid.clone()),
+            ("aid".to_string(), self.aid.to_string()),
+            ("purl".to_string(), self.url(page)),
+        ];
+        if self.pages.len() > 1 {
+            res.push(("album".to_string(), self.title.clone()));
+            res.push(("track".to_string(), page.p.to_string()));
+        }
+        if !self.tags.is_empty() {
+            res.push(("keywords".to_string(), self.tags.join(",")));
+        }
+        res.retain(|(_, v)| !v.is_empty());
+        res
+    }
+}
+
+impl fmt::Display for VideoInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}（{}），UP主 {}", self.title, self.bvid, self.uploader)
+    }
+}
+
+// 把Unix时间戳格式化为北京时间的日期，如“2023-10-01”
+pub fn format_date(timestamp: i64) -> String {
+    if timestamp <= 0 {
+        return String::new();
+    }
+    // 按天数换算公历日期，算法见 http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (timestamp + 8 * 3600).div_euclid(86400) + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// 找出文本中所有的视频链接、短链接和av/bv号，按出现顺序返回，去掉完全相同的项
+// 同一视频的不同写法（如av号和bv号）要解析后才能判断，由调用方按bvid去重
+pub fn extract_video_refs(text: &str) -> Vec<String> {
+    let mut res: Vec<String> = Vec::new();
+    for t in Regex::new(REG_VIDEO_REF).unwrap().find_iter(text) {
+        let t = t.unwrap().as_str();
+        // 不带协议的短链接补上https，parse_video_id才能请求
+        let t = if t.starts_with("b23.tv/") { format!("https://{}", t) } else { clean_video_url(t) };
+        if !res.contains(&t) {
+            res.push(t);
+        }
+    }
+    res
+}
+
+// 将用户输入的视频url、短链接、av/bv号等统一处理成av/bv号，方便后续请求
+pub fn parse_video_id(input: &str) -> Result<VideoId> {
+    let reg_bvid = Regex::new(REG_BVID).unwrap();
+    let reg_avid = Regex::new(REG_AVID).unwrap();
+    let reg_url = Regex::new(REG_URL).unwrap();
+    let reg_short_url = Regex::new(REG_SHORT_URL).unwrap();
+    let url_to_id = |a: &str| -> Result<VideoId> {
+        let (processed_url, end) = match reg_url.captures(a).unwrap() {
+            Some(t) => (t[0].to_string(), t.get(0).unwrap().end()),
+            None => return Err(Error::InvalidInput("解析视频Url出错".into()))
+        };
+        let mut res = VideoId::new(&processed_url)?;
+        (res.page, res.start) = url_params(&a[end..]);
+        Ok(res)
+    };
+    if reg_url.is_match(input).unwrap() {
+        url_to_id(input)
+    } else if reg_bvid.is_match(input).unwrap() || reg_avid.is_match(input).unwrap() {
+        VideoId::new(input)
+    } else if reg_short_url.is_match(input).unwrap() {
+        let processed_short_url = match reg_short_url.captures(input).unwrap() {
+            Some(t) => t[0].to_string(),
+            None => return Err(Error::InvalidInput("解析短Url出错".into()))
+        };
+        match parse_short_url(&processed_short_url) {
+            Some(t) => url_to_id(&t),
+            None => Err(Error::InvalidInput("该b23.tv短链接无效".into()))
+        }
+    } else {
+        Err(Error::InvalidInput("视频链接无效".into()))
+    }
+}
+
+// 链接中的查询参数，到第一个非URL字符（如分享文案中紧跟的中文、空格）为止
+fn url_query(url: &str) -> Option<&str> {
+    let (_, query) = url.split_once('?')?;
+    let end = query.find(|c: char| !c.is_ascii_graphic() || c == '#').unwrap_or(query.len());
+    Some(&query[..end])
+}
+
+// 取出链接中的分P（p=）和时间点（t=，单位秒）参数，vd_source、spm_id_from等跟踪参数不关心
+fn url_params(url: &str) -> (Option<u32>, Option<f64>) {
+    let (mut page, mut start) = (None, None);
+    for (k, v) in url_query(url).unwrap_or("").split('&').filter_map(|t| t.split_once('=')) {
+        match k {
+            "p" => page = v.parse::<u32>().ok().filter(|t| *t > 0),
+            "t" => start = v.parse::<f64>().ok().filter(|t| t.is_finite() && *t > 0.0),
+            _ => {}
+        }
+    }
+    (page, start)
+}
+
+// 去掉视频链接中的跟踪参数，只保留p和t，如“.../video/BV1xx411c7mD/?p=2&vd_source=…”变为“.../video/BV1xx411c7mD/?p=2”
+fn clean_video_url(url: &str) -> String {
+    let base = url.split(['?', '#']).next().unwrap_or(url);
+    let kept: Vec<&str> = url_query(url).unwrap_or("").split('&')
+        .filter(|t| t.starts_with("p=") || t.starts_with("t="))
+        .collect();
+    if kept.is_empty() {
+        base.to_string()
+    } else {
+        format!("{}?{}", base, kept.join("&"))
+    }
+}
+
+// 解析b23.tv短链接
+fn parse_short_url(short_url: &str) -> Option<String> {
+    let client = req::Client::builder()
+        .redirect(Policy::none())
+        .build()
+        .unwrap();
+    let resp = match client.get(short_url).send() {
+        Ok(t) => t,
+        Err(..) => return None
+    };
+    if resp.status().is_redirection() {
+        resp.headers().get("Location").map(|t| t.to_str().unwrap().to_string())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn avid_of(input: &str) -> Option<u64> {
+        match parse_video_id(input).ok()?.value {
+            VideoIdValue::Avid(t) => Some(t),
+            VideoIdValue::Bvid(_) => None,
+        }
+    }
+
+    #[test]
+    fn converts_between_av_and_bv() {
+        assert_eq!(av_to_bv(170001).unwrap(), "BV17x411w7KC");
+        assert_eq!(bv_to_av("BV17x411w7KC").unwrap(), 170001);
+        // 超过2^32的新av号
+        assert_eq!(av_to_bv(111298867365120).unwrap(), "BV1L9Uoa9EUx");
+        assert_eq!(bv_to_av("BV1L9Uoa9EUx").unwrap(), 111298867365120);
+        for aid in [1, 2, 99999999, 1 << 32, BV_MAX_AID - 1] {
+            assert_eq!(bv_to_av(&av_to_bv(aid).unwrap()).unwrap(), aid);
+        }
+        assert!(av_to_bv(0).is_err());
+        assert!(av_to_bv(BV_MAX_AID).is_err());
+    }
+
+    #[test]
+    fn rejects_mistyped_bvid() {
+        assert!(bv_to_av("BV17x411w7K").is_err());
+        assert!(bv_to_av("BV17x411w7KCC").is_err());
+        assert!(bv_to_av("BV27x411w7KC").is_err());
+        assert!(bv_to_av("BV17x411w7K0").is_err());
+        assert!(bv_to_av("BV1zzzzzzzzz").is_err());
+        assert!(VideoId::new("BV1zzzzzzzzz").is_err());
+    }
+
+    #[test]
+    fn video_id_has_both_forms() {
+        let id = VideoId::new("av170001").unwrap();
+        assert_eq!((id.aid().unwrap(), id.bvid().unwrap().as_str()), (170001, "BV17x411w7KC"));
+        let id = VideoId::new("BV17x411w7KC").unwrap();
+        assert_eq!((id.aid().unwrap(), id.bvid().unwrap().as_str()), (170001, "BV17x411w7KC"));
+    }
+
+    #[test]
+    fn av_inside_bvid_is_not_an_avid() {
+        assert!(!matches!(VideoId::new("BV1av411c7mD").map(|t| t.value), Ok(VideoIdValue::Avid(_))));
+        assert_eq!(avid_of("https://www.bilibili.com/video/BV1av4y1x7jm"), None);
+        assert_eq!(avid_of("BV1av4y1x7jm"), None);
+        assert_eq!(avid_of("https://www.bilibili.com/video/av170001/"), Some(170001));
+        assert_eq!(avid_of("看看av170001吧"), Some(170001));
+    }
+
+    #[test]
+    fn extracts_every_ref_in_text() {
+        let text = "【标题】 https://www.bilibili.com/video/BV17x411w7KC/来看 b23.tv/AbC123 av170001，BV17x411w7KC \
+                    http://b23.tv/xyz https://www.bilibili.com/video/BV17x411w7KC/";
+        assert_eq!(extract_video_refs(text), vec![
+            "https://www.bilibili.com/video/BV17x411w7KC/",
+            "https://b23.tv/AbC123",
+            "av170001",
+            "BV17x411w7KC",
+            "http://b23.tv/xyz",
+        ]);
+        assert_eq!(extract_video_refs("BV1av4y1x7jm"), vec!["BV1av4y1x7jm"]);
+        assert!(extract_video_refs("没有视频的文本 xav123 BV123").is_empty());
+    }
+
+    #[test]
+    fn keeps_page_and_time_and_strips_tracking_params() {
+        let text = "https://www.bilibili.com/video/BV17x411w7KC/?p=7&vd_source=abc&t=120#reply来看 \
+                    https://m.bilibili.com/video/av170001?spm_id_from=333&share_source=copy";
+        assert_eq!(extract_video_refs(text), vec![
+            "https://www.bilibili.com/video/BV17x411w7KC/?p=7&t=120",
+            "https://m.bilibili.com/video/av170001",
+        ]);
+        let id = parse_video_id("https://www.bilibili.com/video/BV17x411w7KC?spm_id_from=1&p=3&t=90.5").unwrap();
+        assert_eq!((id.page, id.start), (Some(3), Some(90.5)));
+        let id = parse_video_id("https://www.bilibili.com/video/BV17x411w7KC?p=0&t=-1").unwrap();
+        assert_eq!((id.page, id.start), (None, None));
+        let id = parse_video_id("BV17x411w7KC").unwrap();
+        assert_eq!((id.page, id.start), (None, None));
+    }
+}
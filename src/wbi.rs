@@ -0,0 +1,42 @@
+use std::ops::Add;
+use std::time::SystemTime;
+
+use fancy_regex::Regex;
+
+use crate::{REG_WBI_KEY, WBI_KEY_TAB};
+use crate::error::{Error, Result};
+
+// 计算B站Wbi签名，见 https://socialsisteryi.github.io/bilibili-API-collect/docs/misc/sign/wbi.html
+pub fn wbi_sign_para(mut paras: Vec<(String, String)>, img_url: &str, sub_url: &str) -> Result<Vec<(String, String)>> {
+    let reg_wbi = Regex::new(REG_WBI_KEY).unwrap();
+    let img_key = match reg_wbi.captures(img_url).unwrap() {
+        Some(t) => t[0].to_string(),
+        None => return Err(Error::WbiSign("参数不合法".into()))
+    };
+    let sub_key = match reg_wbi.captures(sub_url).unwrap() {
+        Some(t) => t[0].to_string(),
+        None => return Err(Error::WbiSign("参数不合法".into()))
+    };
+    let key = format!("{}{}", img_key, sub_key);
+    let mut mixin_key = String::new();
+    let mut buffer = [0; 4];
+    for i in WBI_KEY_TAB.iter() {
+        mixin_key = mixin_key.add(key.chars().nth(*i as usize).unwrap().encode_utf8(&mut buffer))
+    };
+    let mixin_key = &mixin_key[0..32];
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    paras.push(("wts".into(), now.to_string()));
+    paras.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    let mut query = Vec::new();
+    let reg_filter = Regex::new(r"[!'()*]").unwrap();
+    for i in paras.iter() {
+        let val = reg_filter.replace(&i.1, "").to_string();
+        let key = urlencoding::encode(&i.0).to_string().to_lowercase();
+        let val = urlencoding::encode(&val).to_string().to_lowercase();
+        query.push(format!("{}={}", key, val));
+    };
+    let query = query.join("&");
+    let wbi_sign = format!("{:x}", md5::compute(query.add(mixin_key)));
+    paras.push(("w_rid".into(), wbi_sign));
+    Ok(paras)
+}